@@ -1,3 +1,3 @@
 #!/bin/sh
 
-oha -z 15s -c 1000 --disable-keepalive http://127.0.0.1:8080/combo/load_test 
\ No newline at end of file
+oha -z 15s -c 1000 --disable-keepalive http://127.0.0.1:8080/combo/load_test -H "X-Api-Key: dev-local-key" 
\ No newline at end of file
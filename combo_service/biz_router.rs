@@ -1,346 +1,362 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use axum::{
-    extract::{Json, Path, State},
-    http::StatusCode,
+    extract::{Json, Path, Query, State},
+    http::{HeaderValue, StatusCode},
     response::IntoResponse,
     routing::{delete, get, patch, post},
     Router,
 };
-use hyper::HeaderMap;
-use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
 use shared::{
+    breaker::{BreakerSnapshot, BreakerState, CircuitBreaker},
+    change_feed::check_name,
+    client::{ClientError, EntityClient, PropertyClient, ResourceClient},
+    context::current_logid,
     prelude::*,
-    result::LoggableResult,
     state::{
-        combo::{self, Combo, MaybeCombo, PartialCombo},
-        entity::{self, Entity},
-        property::{self, PartialProperty, Property},
-    }, header_helper,
+        combo::{Combo, MaybeCombo, PartialCombo},
+        entity::Entity,
+        property::Property,
+        query::{BatchGet, BatchResult, Filterable, Item, ListQuery, Page},
+        version::Precondition,
+    },
 };
 
-use tokio::sync::OnceCell;
 use tracing::{error, info, warn};
 
-pub fn get_router() -> Router<Arc<Client>> {
+use crate::{
+    cache::ComboCache,
+    outbox::{Outbox, Outcome},
+    saga::Saga,
+    state::{ComboState, EntityLoader, PropertyLoader, UpstreamTimeout},
+    util::{fan_out, within},
+};
+
+/// Set on a partial `GET /combo/:name` response, naming what is missing.
+pub const PARTIAL_HEADER: &str = "x-combo-partial";
+
+pub fn get_router() -> Router<ComboState> {
     Router::new()
+        .route("/health/detail", get(health_detail))
+        .route("/cache/stats", get(cache_stats))
+        .route("/combo", get(list_combo))
+        // See entity_microservice: this is "/combo" followed by ":batchGet".
+        .route("/combo:method", post(combo_method))
         .route("/combo/:name", get(get_combo))
         .route("/combo/:name", delete(delete_combo))
         .route("/combo/:name", post(post_combo))
         .route("/combo/:name", patch(patch_combo))
 }
 
-async fn get_port_for_service(service: &str) -> String {
-    OnceCell::<String>::new()
-        .get_or_init(|| async {
-            let service_key = format!("{}_port", service).to_uppercase();
+/// Circuit breaker state for each upstream; `degraded` while any is not
+/// closed.
+async fn health_detail(
+    State(entities): State<EntityClient>,
+    State(properties): State<PropertyClient>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let upstreams: Vec<BreakerSnapshot> = [entities.breaker(), properties.breaker()]
+        .into_iter()
+        .flatten()
+        .map(CircuitBreaker::snapshot)
+        .collect();
+
+    let status = match upstreams.iter().all(|upstream| upstream.state == BreakerState::Closed) {
+        true => "ok",
+        false => "degraded",
+    };
 
-            info!("got service_key={service_key}");
+    Ok(Json(json!({ "status": status, "upstreams": upstreams })))
+}
 
-            match std::env::var(service_key) {
-                Ok(port) => {
-                    info!("got service={service}, port={port}");
-                    port
-                }
-                Err(_) => {
-                    warn!("using default port for service={service}");
-                    "8080".to_string()
-                }
-            }
-        })
-        .await
-        .clone()
+/// Hit/miss counters for the combo cache.
+async fn cache_stats(State(cache): State<Arc<ComboCache>>) -> Result<impl IntoResponse, StatusCode> {
+    Ok(Json(cache.stats()))
 }
 
-async fn get_canonical_name_for_service(service: &str) -> String {
-    OnceCell::<String>::new()
-        .get_or_init(|| async {
-            let service_key = format!("{}_address", service).to_uppercase();
+async fn list_combo(
+    State(entities): State<EntityClient>,
+    State(properties): State<PropertyClient>,
+    State(timeout): State<UpstreamTimeout>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let deadline = timeout.deadline();
 
-            info!("got service_key={service_key}");
+    info!("req: params={:?}", params);
 
-            match std::env::var(service_key) {
-                Ok(address) => {
-                    info!("got service={service}, address={address}");
-                    address
-                }
-                Err(_) => {
-                    warn!("using default address for service={service}");
-                    service.to_string()
-                }
+    let query = ListQuery::from_params::<Combo>(params)?;
+
+    // Paging and entity filters are applied by entity_microservice, property
+    // filters here, so a page can come back shorter than the limit.
+    let entity_query = query.retain_filters(Entity::FIELDS);
+    let property_query = query.retain_filters(Property::FIELDS);
+
+    let entity_page = within(deadline, entities.list(&entity_query)).await?;
+
+    // A page holds at most `MAX_LIMIT` entities, as many as a batch can.
+    let names: Vec<String> = entity_page.items.iter().map(|entity| entity.name.clone()).collect();
+
+    let mut found = match names.is_empty() {
+        true => HashMap::new(),
+        false => by_name(within(deadline, properties.batch_get(&names)).await?),
+    };
+
+    let mut items = Vec::with_capacity(entity_page.items.len());
+
+    for entity in entity_page.items {
+        let property = match found.remove(&entity.name) {
+            Some(property) => property,
+            None => {
+                warn!("skipping entity without property: name={}", entity.name);
+                continue;
             }
-        })
-        .await
-        .clone()
-}
+        };
 
-async fn get_address_for_servive(service: &str) -> String {
-    let port = get_port_for_service(service).await;
-    let domain = get_canonical_name_for_service(service).await;
+        if !property_query.matches_value(&property) {
+            continue;
+        }
 
-    format!("http://{domain}:{port}", domain = domain, port = port)
-}
+        items.push(Item {
+            name: entity.name,
+            version: None,
+            value: Combo::from((entity.value, property)),
+        });
+    }
 
-async fn get_path_for_service(service: &str, path: &str) -> String {
-    let address = get_address_for_servive(service).await;
+    info!("resp: items={}, next_cursor={:?}", items.len(), entity_page.next_cursor);
 
-    format!("{address}/{path}")
+    Ok(Json(Page {
+        items,
+        next_cursor: entity_page.next_cursor,
+    }))
 }
 
+#[derive(Debug, Default, Deserialize)]
+struct GetComboParams {
+    /// Return the entity alone, with `property`/`value` null, when it has no
+    /// property yet instead of 404.
+    #[serde(default)]
+    partial: bool,
+}
+
+/// Single lookups go through the loaders, so concurrent requests for
+/// different combos share one batch call per upstream.
 async fn get_combo(
-    State(client): State<Arc<Client>>,
+    State(entity_loader): State<EntityLoader>,
+    State(property_loader): State<PropertyLoader>,
+    State(cache): State<Arc<ComboCache>>,
+    State(timeout): State<UpstreamTimeout>,
     Path(name): Path<String>,
-    headers: HeaderMap
+    Query(params): Query<GetComboParams>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let id: String = shared::header_helper::get_logid(headers).await;
+    info!("req: name={}, params={:?}", name, params);
 
-    info!("req: name={}", name);
+    let generation = cache.generation();
+
+    if let Some(combo) = cache.get(&name) {
+        info!("resp: cache hit, name={}", name);
 
-    let entity_address = get_path_for_service("entity", format!("entity/{name}").as_str()).await;
+        return Ok(match params.partial {
+            true => Json(MaybeCombo::from(combo)).into_response(),
+            false => Json(combo).into_response(),
+        });
+    }
 
-    let property_address =
-        get_path_for_service("property", format!("property/{name}").as_str()).await;
+    let (entity, property) =
+        fan_out(timeout.deadline(), entity_loader.load(&name), property_loader.load(&name)).await?;
 
-    info!("requesting entity from: entity_address={}", entity_address);
+    let entity = entity.ok_or(StatusCode::NOT_FOUND)?;
 
-    let entity_response = match client.get(entity_address).header("logid", &id).send().await {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error requesting entity: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let response = match (property, params.partial) {
+        (Some(property), partial) => {
+            let combo = Combo::from((entity, property));
 
-    info!("entity_response={:?}", entity_response);
+            cache.insert(&name, combo.clone(), generation);
 
-    let entity: entity::Entity = match entity_response.status() {
-        reqwest::StatusCode::NOT_FOUND => return Err(StatusCode::NOT_FOUND),
-        reqwest::StatusCode::OK => match entity_response.json().await {
-            Ok(entity) => entity,
-            Err(e) => {
-                error!("error parsing entity: error={:?}", e);
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            match partial {
+                true => Json(MaybeCombo::from(combo)).into_response(),
+                false => Json(combo).into_response(),
             }
-        },
-        s => {
-            error!("unexpected status code: status_code={:?}", s);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
-    };
+        (None, false) => return Err(StatusCode::NOT_FOUND),
+        (None, true) => {
+            info!("resp: property missing, returning partial combo: name={}", name);
 
-    info!(
-        "requesting property from: property_address={}",
-        property_address
-    );
-
-    let property_response = match client
-        .get(property_address)
-        .header("logid", &id)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error requesting property: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            let mut response = Json(MaybeCombo::from((entity, None))).into_response();
+            response.headers_mut().insert(PARTIAL_HEADER, HeaderValue::from_static("property"));
+            response
         }
     };
 
-    info!("property_response={:?}", property_response);
+    Ok(response)
+}
+
+/// `POST /combo:batchGet`: the combos for a list of names, in request order.
+/// Names missing from either service are listed under `missing`.
+async fn combo_method(
+    State(entities): State<EntityClient>,
+    State(properties): State<PropertyClient>,
+    State(cache): State<Arc<ComboCache>>,
+    State(timeout): State<UpstreamTimeout>,
+    Path(method): Path<String>,
+    Json(batch): Json<BatchGet>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if method != ":batchGet" {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    info!("req: names={}", batch.names.len());
+
+    batch.validate()?;
+
+    let generation = cache.generation();
+
+    let mut cached = HashMap::new();
+    let mut fetch = Vec::new();
+    let mut seen = HashSet::new();
+
+    for name in &batch.names {
+        if !seen.insert(name.as_str()) {
+            continue;
+        }
 
-    let property = match property_response.status(){
-        reqwest::StatusCode::NOT_FOUND => return Err(StatusCode::NOT_FOUND),
-        reqwest::StatusCode::OK => match property_response.json().await {
-            Ok(entity) => entity,
-            Err(e) => {
-                error!("error parsing property: error={:?}", e);
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        match cache.get(name) {
+            Some(combo) => {
+                cached.insert(name.clone(), combo);
             }
-        },
-        s => {
-            error!("unexpected status code: status_code={:?}", s);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            None => fetch.push(name.clone()),
         }
+    }
+
+    let (found_entities, found_properties) = match fetch.is_empty() {
+        true => (HashMap::new(), HashMap::new()),
+        false => {
+            let (entity_batch, property_batch) = fan_out(
+                timeout.deadline(),
+                entities.batch_get(&fetch),
+                properties.batch_get(&fetch),
+            )
+            .await?;
+
+            (by_name(entity_batch), by_name(property_batch))
+        }
+    };
+
+    let mut result = BatchResult {
+        items: Vec::with_capacity(batch.names.len()),
+        missing: Vec::new(),
     };
 
-    let combo = Combo::from((entity, property));
+    for name in batch.names {
+        let combo = match cached.get(&name) {
+            Some(combo) => combo.clone(),
+            None => match (found_entities.get(&name), found_properties.get(&name)) {
+                (Some(entity), Some(property)) => {
+                    let combo = Combo::from((entity.clone(), property.clone()));
 
-    Ok(Json(combo))
+                    cache.insert(&name, combo.clone(), generation);
+
+                    combo
+                }
+                _ => {
+                    result.missing.push(name);
+                    continue;
+                }
+            },
+        };
+
+        result.items.push(Item {
+            name,
+            version: None,
+            value: combo,
+        });
+    }
+
+    info!("resp: items={}, missing={}", result.items.len(), result.missing.len());
+
+    Ok(Json(result))
 }
 
 async fn post_combo(
-    headers: HeaderMap,
-    State(client): State<Arc<Client>>,
+    State(outbox): State<Arc<Outbox>>,
+    State(cache): State<Arc<ComboCache>>,
+    State(entities): State<EntityClient>,
+    State(properties): State<PropertyClient>,
+    State(timeout): State<UpstreamTimeout>,
     Path(name): Path<String>,
     Json(payload): Json<MaybeCombo>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let id: String = shared::header_helper::get_logid(headers).await;
+    let id = current_logid().unwrap_or_else(generate_trace_id);
 
     info!("req: payload={:?}", payload);
 
-    // Build request
-
-    let entity_address = get_path_for_service("entity", format!("entity/{name}").as_str()).await;
+    check_name(&name)?;
 
-    let property_address =
-        get_path_for_service("property", format!("property/{name}").as_str()).await;
+    // Don't record a write in the outbox that can only be compensated.
+    if !entities.available() || !properties.available() {
+        warn!("resp: status=503, upstream circuit open");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
 
-    // Build Bodies
-    let entity_body = match serde_json::to_string(&payload) {
-        Ok(body) => body,
-        Err(e) => {
-            error!("error serializing entity: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let entity: Entity = payload.clone().into();
 
     let property = match payload.try_into() {
         Ok(property) => property,
-        Err(e) => {
+        Err(_) => {
             warn!("Using default property");
             Property::default()
         }
     };
 
-    let property_body = match serde_json::to_string(&property) {
-        Ok(body) => body,
-        Err(e) => {
-            error!("error serializing property: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    // Send requests
-
-    info!("sending entity post to: entity_address={}", entity_address);
-
-    let entity_response = match client
-        .post(entity_address)
-        .header("logid", &id)
-        .header("content-type", "application/json")
-        .body(entity_body)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error sending entity: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    if !entity_response.status().is_success() {
-        error!("unexpected status code: status_code={:?}, reason={:?}", entity_response.status(), entity_response.status().canonical_reason());
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
+    let deadline = timeout.deadline();
 
-    let property_response = match client
-        .post(&property_address)
-        .header("logid", &id)
-        .header("content-type", "application/json")
-        .body(property_body)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error sending property: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    // Snapshot existing, so a failed write can put them back
 
-    info!("entity_response={:?}", entity_response);
+    let previous = fan_out(deadline, entities.find(&name), properties.find(&name)).await?;
 
-    info!(
-        "sending property post to: property_address={}",
-        property_address
-    );
+    let outcome = outbox.submit(&name, &id, (entity, property), previous, deadline).await;
 
-    info!("property_response={:?}", property_response);
+    // Whatever happened upstream, the cached combo may no longer be right.
+    cache.invalidate(&name);
 
-    if !property_response.status().is_success() {
-        error!("unexpected status code: status_code={:?}, reason={:?}", property_response.status(), property_response.status().canonical_reason());
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    match outcome? {
+        Outcome::Completed => Ok(StatusCode::CREATED.into_response()),
+        Outcome::Compensated(reason) => {
+            error!("combo write rolled back: name={}, reason={}", name, reason);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Outcome::Pending(reason) => {
+            error!("combo write failed, rollback pending: name={}, reason={}", name, reason);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
     }
-
-    Ok(StatusCode::CREATED.into_response())
 }
 
 async fn patch_combo(
-    headers: HeaderMap,    
-    State(client): State<Arc<Client>>,
+    State(entities): State<EntityClient>,
+    State(properties): State<PropertyClient>,
+    State(cache): State<Arc<ComboCache>>,
+    State(timeout): State<UpstreamTimeout>,
     Path(name): Path<String>,
     Json(payload): Json<PartialCombo>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let id: String = shared::header_helper::get_logid(headers).await;
+    let deadline = timeout.deadline();
 
     info!("req: payload={:?}", payload);
 
-    // Build Target
-
-    let entity_address = get_path_for_service("entity", format!("entity/{name}").as_str()).await;
-
-    let property_address =
-        get_path_for_service("property", format!("property/{name}").as_str()).await;
-
-    // Get existing
-    info!("requesting entity from: entity_address={}", entity_address);
-
-    let entity_response = match client
-        .get(&entity_address)
-        .header("logid", &id)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error requesting entity: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    info!("entity_response={:?}", entity_response);
+    // Snapshot existing
 
-    let existing_entity: entity::Entity = match entity_response.json().await {
-        Ok(entity) => entity,
-        Err(e) => {
-            error!("error parsing entity: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let (existing_entity, existing_property) =
+        fan_out(deadline, entities.get(&name), properties.find(&name)).await?;
 
-    info!(
-        "requesting property from: property_address={}",
-        property_address
-    );
-
-    let property_response = match client
-        .get(&property_address)
-        .header("logid", &id)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error requesting property: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    info!("property_response={:?}", property_response);
-
-    let existing_property = match property_response.status() {
-        reqwest::StatusCode::OK => match property_response.json::<Property>().await {
-            Ok(property) => property,
-            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
-        },
-        _ => Property::default(),
-    };
-
-    info!("existing_entity={:?}", existing_entity);
+    info!("existing_entity={:?}, existing_property={:?}", existing_entity, existing_property);
 
     // Update existing
-    let existing = Combo::from((existing_entity, existing_property));
+    let existing = Combo::from((existing_entity.clone(), existing_property.clone().unwrap_or_default()));
 
     let updated_combo = payload.merge(&existing);
 
@@ -348,125 +364,137 @@ async fn patch_combo(
 
     let updated_property: Property = updated_combo.clone().into();
 
-    // Build Updates Bodies
-    let entity_body = match serde_json::to_string(&updated_entity) {
-        Ok(body) => body,
-        Err(e) => {
-            error!("error serializing entity: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    let property_body = match serde_json::to_string(&updated_property) {
-        Ok(body) => body,
-        Err(e) => {
-            error!("error serializing property: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    // Send updates
-
-    info!("sending entity patch to: entity_address={}", entity_address);
-
-    let entity_response = match client
-        .post(entity_address)
-        .header("logid", &id)
-        .header("content-type", "application/json")
-        .body(entity_body)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error sending entity: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    info!("entity_response={:?}", entity_response);
-
-    info!(
-        "sending property patch to: property_address={}",
-        property_address
-    );
-
-    let property_response = match client
-        .post(property_address)
-        .header("logid", &id)
-        .header("content-type", "application/json")
-        .body(property_body)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error sending property: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    info!("property_response={:?}", property_response);
+    // Send updates together, restoring the snapshot on failure
+
+    let saga = Saga::new(timeout.0)
+        .step(
+            "entity",
+            {
+                let (entities, name, updated_entity) = (entities.clone(), name.clone(), updated_entity.clone());
+                move || async move { entities.set_if(&name, &updated_entity, &Precondition::none()).await }
+            },
+            {
+                let (entities, name) = (entities.clone(), name.clone());
+                move |written| async move { undo(&entities, &name, Some(&updated_entity), written, Some(existing_entity)).await }
+            },
+        )
+        .step(
+            "property",
+            {
+                let (properties, name, updated_property) = (properties.clone(), name.clone(), updated_property.clone());
+                move || async move { properties.set_if(&name, &updated_property, &Precondition::none()).await }
+            },
+            {
+                let (properties, name) = (properties.clone(), name.clone());
+                move |written| async move { undo(&properties, &name, Some(&updated_property), written, existing_property).await }
+            },
+        );
+
+    let result = saga.run(deadline).await;
+
+    cache.invalidate(&name);
+
+    if let Err(e) = result {
+        error!("resp: status=500, error={:?}", e);
+        return Ok((StatusCode::INTERNAL_SERVER_ERROR, Json(e)).into_response());
+    }
 
-    Ok(Json(updated_combo))
+    Ok(Json(updated_combo).into_response())
 }
 
 async fn delete_combo(
-    headers: HeaderMap,
-    State(client): State<Arc<Client>>,
+    State(entities): State<EntityClient>,
+    State(properties): State<PropertyClient>,
+    State(cache): State<Arc<ComboCache>>,
+    State(timeout): State<UpstreamTimeout>,
     Path(name): Path<String>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let id: String = shared::header_helper::get_logid(headers).await;
+    let deadline = timeout.deadline();
 
     info!("req: name={}", name);
 
-    let entity_address = get_path_for_service("entity", format!("entity/{name}").as_str()).await;
-
-    let property_address =
-        get_path_for_service("property", format!("property/{name}").as_str()).await;
+    // Snapshot existing, so a failed delete can put them back
+
+    let (existing_entity, existing_property) =
+        fan_out(deadline, entities.find(&name), properties.find(&name)).await?;
+
+    info!("existing_entity={:?}, existing_property={:?}", existing_entity, existing_property);
+
+    let saga = Saga::new(timeout.0)
+        .step(
+            "entity",
+            {
+                let (entities, name) = (entities.clone(), name.clone());
+                move || async move { entities.delete(&name).await.map(|_| None) }
+            },
+            {
+                let (entities, name) = (entities.clone(), name.clone());
+                move |written| async move { undo(&entities, &name, None, written, existing_entity).await }
+            },
+        )
+        .step(
+            "property",
+            {
+                let (properties, name) = (properties.clone(), name.clone());
+                move || async move { properties.delete(&name).await.map(|_| None) }
+            },
+            {
+                let (properties, name) = (properties.clone(), name.clone());
+                move |written| async move { undo(&properties, &name, None, written, existing_property).await }
+            },
+        );
+
+    let result = saga.run(deadline).await;
+
+    cache.invalidate(&name);
+
+    if let Err(e) = result {
+        error!("resp: status=500, error={:?}", e);
+        return Ok((StatusCode::INTERNAL_SERVER_ERROR, Json(e)).into_response());
+    }
 
-    info!("deleting entity from: entity_address={}", entity_address);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
 
-    let entity_response = match client
-        .delete(entity_address)
-        .header("logid", &id)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error requesting entity: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+/// Undoes a saga step that wrote `wrote` to `name`, or deleted it when
+/// `None`, by putting back `snapshot`, or deleting `name` when there wasn't
+/// one.
+///
+/// Only done while `name` still holds the step's write: at `written`, the
+/// version the step was told it wrote, or else at whatever version holds
+/// `wrote` now. Anything written since is left alone and reported as a
+/// conflict.
+async fn undo<T>(client: &ResourceClient<T>, name: &str, wrote: Option<&T>, written: Option<u64>, snapshot: Option<T>) -> Result<(), String>
+where
+    T: Serialize + DeserializeOwned + PartialEq,
+{
+    let conflict = || format!("conflict: {name} was written again since, not restoring it");
+
+    let precondition = match (wrote, written) {
+        (None, _) if snapshot.is_none() => return Ok(()),
+        (None, _) => Precondition::if_absent(),
+        (Some(_), Some(version)) => Precondition::if_match(version),
+        // The step failed without saying, so it may not have landed.
+        (Some(wrote), None) => match client.find_versioned(name).await.map_err(|e| e.to_string())? {
+            Some(current) if current.value == *wrote => Precondition::if_match(current.version),
+            current if current.as_ref().map(|current| &current.value) == snapshot.as_ref() => return Ok(()),
+            _ => return Err(conflict()),
+        },
     };
 
-    info!(
-        "response for entity deletion: response={:?}",
-        entity_response
-    );
-
-    info!(
-        "deleting property from: property_address={}",
-        property_address
-    );
-
-    let property_response = match client
-        .delete(property_address)
-        .header("logid", &id)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => {
-            error!("error requesting property: error={:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+    info!("restoring snapshot: name={}, precondition={:?}", name, precondition);
+
+    let restored = match snapshot {
+        Some(value) => client.set_if(name, &value, &precondition).await.map(|_| ()),
+        None => client.delete_if(name, &precondition).await,
     };
 
-    info!(
-        "response for property deletion: response={:?}",
-        property_response
-    );
+    match restored {
+        Err(ClientError::Rejected(reqwest::StatusCode::PRECONDITION_FAILED)) => Err(conflict()),
+        restored => restored.map_err(|e| e.to_string()),
+    }
+}
 
-    Ok(StatusCode::NO_CONTENT.into_response())
+fn by_name<T>(batch: BatchResult<T>) -> HashMap<String, T> {
+    batch.items.into_iter().map(|item| (item.name, item.value)).collect()
 }
@@ -0,0 +1,303 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use shared::{client::ResourceClient, state::combo::Combo};
+use tracing::{error, info, warn};
+
+const DEFAULT_CAPACITY: usize = 1000;
+const DEFAULT_TTL_MS: u64 = 5000;
+const RECONNECT_DELAY: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+struct Entries {
+    combos: LruCache<String, (Instant, Combo)>,
+    /// The `clock` at each key's latest invalidation, for as many keys as
+    /// there are combos.
+    invalidated: LruCache<String, u64>,
+    /// Combos fetched before this may have missed an invalidation since
+    /// forgotten, or a `clear`.
+    stale_before: u64,
+}
+
+/// LRU cache of assembled combos, each kept for at most `ttl`.
+///
+/// Writes through combo_service invalidate their key, and with
+/// `spawn_invalidator` so do writes made directly to entity_microservice or
+/// property_microservice.
+pub struct ComboCache {
+    entries: Option<Mutex<Entries>>,
+    ttl: Duration,
+    /// Ticks on every invalidation.
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl ComboCache {
+    /// A `capacity` of zero disables caching.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: NonZeroUsize::new(capacity).map(|capacity| {
+                Mutex::new(Entries {
+                    combos: LruCache::new(capacity),
+                    invalidated: LruCache::new(capacity),
+                    stale_before: 0,
+                })
+            }),
+            ttl,
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads `COMBO_CACHE_CAPACITY` and `COMBO_CACHE_TTL_MS`.
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("COMBO_CACHE_CAPACITY")
+            .ok()
+            .and_then(|capacity| capacity.parse().ok())
+            .unwrap_or(DEFAULT_CAPACITY);
+
+        let ttl = std::env::var("COMBO_CACHE_TTL_MS")
+            .ok()
+            .and_then(|ttl| ttl.parse().ok())
+            .unwrap_or(DEFAULT_TTL_MS);
+
+        info!("combo cache: capacity={capacity}, ttl_ms={ttl}");
+
+        Self::new(capacity, Duration::from_millis(ttl))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Combo> {
+        let entries = self.entries.as_ref()?;
+        let mut entries = entries.lock().unwrap();
+
+        let hit = match entries.combos.get(name) {
+            Some((cached_at, combo)) if cached_at.elapsed() < self.ttl => Some(combo.clone()),
+            Some(_) => {
+                entries.combos.pop(name);
+                None
+            }
+            None => None,
+        };
+
+        match hit {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        hit
+    }
+
+    /// Taken before fetching combos and handed back to `insert`, so a combo
+    /// fetched before its key is invalidated isn't cached after it.
+    pub fn generation(&self) -> u64 {
+        self.clock.load(Ordering::Acquire)
+    }
+
+    pub fn insert(&self, name: &str, combo: Combo, generation: u64) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+
+        let mut entries = entries.lock().unwrap();
+
+        // Checked under the lock, so no invalidation can come between.
+        let invalidated = entries.invalidated.peek(name).is_some_and(|&at| at > generation);
+
+        if invalidated || generation < entries.stale_before {
+            return;
+        }
+
+        entries.combos.put(name.to_string(), (Instant::now(), combo));
+    }
+
+    pub fn invalidate(&self, name: &str) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+
+        let mut entries = entries.lock().unwrap();
+
+        let now = self.clock.fetch_add(1, Ordering::AcqRel) + 1;
+
+        // Forgetting another key's invalidation means no longer knowing
+        // whether combos fetched before it are stale.
+        if let Some((evicted, at)) = entries.invalidated.push(name.to_string(), now) {
+            if evicted != name {
+                entries.stale_before = entries.stale_before.max(at);
+            }
+        }
+
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+        entries.combos.pop(name);
+    }
+
+    pub fn clear(&self) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+
+        let mut entries = entries.lock().unwrap();
+
+        let now = self.clock.fetch_add(1, Ordering::AcqRel) + 1;
+
+        entries.stale_before = now;
+        entries.invalidated.clear();
+        entries.combos.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (size, capacity) = match &self.entries {
+            Some(entries) => {
+                let entries = entries.lock().unwrap();
+                (entries.combos.len(), entries.combos.cap().get())
+            }
+            None => (0, 0),
+        };
+
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            size,
+            capacity,
+        }
+    }
+
+    /// Follows `client`'s change feed, invalidating each changed key.
+    ///
+    /// Anything could change while the feed is down, so the whole cache is
+    /// cleared whenever it disconnects and again when it reconnects.
+    pub fn spawn_invalidator<T>(self: &Arc<Self>, client: ResourceClient<T>, upstream: &'static str)
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let cache = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match client.watch(None).await {
+                    Ok(mut feed) => {
+                        info!("watching for changes: upstream={upstream}");
+                        cache.clear();
+
+                        loop {
+                            match feed.next().await {
+                                Ok(Some(event)) => match serde_json::from_str::<ChangedKey>(&event.data) {
+                                    Ok(changed) => cache.invalidate(&changed.key),
+                                    Err(e) => error!("error parsing change: upstream={upstream}, error={e}"),
+                                },
+                                Ok(None) => {
+                                    warn!("change feed closed: upstream={upstream}");
+                                    break;
+                                }
+                                Err(e) => {
+                                    warn!("error reading change feed: upstream={upstream}, error={e}");
+                                    break;
+                                }
+                            }
+                        }
+
+                        cache.clear();
+                    }
+                    Err(e) => warn!("error watching for changes: upstream={upstream}, error={e}"),
+                }
+
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangedKey {
+    key: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn combo() -> Combo {
+        serde_json::from_value(serde_json::json!({
+            "origin": "NZ",
+            "colour": "red",
+            "property": "p",
+            "value": "v",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn invalidating_a_key_only_blocks_its_own_stale_insert() {
+        let cache = ComboCache::new(10, Duration::from_secs(60));
+        let generation = cache.generation();
+
+        cache.invalidate("a");
+
+        cache.insert("a", combo(), generation);
+        cache.insert("b", combo(), generation);
+
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+
+        cache.insert("a", combo(), cache.generation());
+        assert!(cache.get("a").is_some());
+    }
+
+    #[test]
+    fn clear_blocks_every_stale_insert() {
+        let cache = ComboCache::new(10, Duration::from_secs(60));
+        let generation = cache.generation();
+
+        cache.clear();
+        cache.insert("b", combo(), generation);
+
+        assert!(cache.get("b").is_none());
+    }
+
+    #[test]
+    fn forgotten_invalidations_block_older_inserts() {
+        let cache = ComboCache::new(2, Duration::from_secs(60));
+        let generation = cache.generation();
+
+        // "a" falls out of the two invalidations remembered.
+        for name in ["a", "b", "c"] {
+            cache.invalidate(name);
+        }
+
+        cache.insert("a", combo(), generation);
+        assert!(cache.get("a").is_none());
+
+        cache.insert("a", combo(), cache.generation());
+        assert!(cache.get("a").is_some());
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let cache = ComboCache::new(10, Duration::ZERO);
+
+        cache.insert("a", combo(), cache.generation());
+
+        assert!(cache.get("a").is_none());
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::http::StatusCode;
+use serde::{de::DeserializeOwned, Serialize};
+use shared::{
+    client::ResourceClient,
+    context::{current_deadline, current_logid, current_principal, scope_deadline, scope_logid, scope_principal},
+    prelude::generate_trace_id,
+};
+use tokio::{sync::oneshot, time::Instant};
+use tracing::{error, info};
+
+const DEFAULT_WINDOW_MS: u64 = 2;
+const DEFAULT_MAX_BATCH: usize = 100;
+
+type Waiter<T> = oneshot::Sender<Result<Option<T>, StatusCode>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoaderConfig {
+    /// How long the first lookup of a batch waits for others to join it.
+    pub window: Duration,
+    /// Names that flush a batch straight away.
+    pub max_batch: usize,
+}
+
+impl LoaderConfig {
+    /// Reads `COMBO_COALESCE_WINDOW_MS` and `COMBO_COALESCE_MAX_BATCH`.
+    pub fn from_env() -> Self {
+        let window = std::env::var("COMBO_COALESCE_WINDOW_MS")
+            .ok()
+            .and_then(|window| window.parse().ok())
+            .unwrap_or(DEFAULT_WINDOW_MS);
+
+        let max_batch = std::env::var("COMBO_COALESCE_MAX_BATCH")
+            .ok()
+            .and_then(|max_batch| max_batch.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BATCH);
+
+        info!("combo loader: window_ms={window}, max_batch={max_batch}");
+
+        Self {
+            window: Duration::from_millis(window),
+            max_batch: max_batch.max(1),
+        }
+    }
+}
+
+struct Batch<T> {
+    /// Unique to the batch, so a window timer only flushes its own.
+    epoch: u64,
+    waiters: HashMap<String, Vec<Waiter<T>>>,
+    logid: Option<String>,
+    deadline: Option<Instant>,
+}
+
+struct Batches<T> {
+    next_epoch: u64,
+    /// The open batch for each principal.
+    pending: HashMap<Option<String>, Batch<T>>,
+}
+
+/// Merges single-name lookups made within a short window into one
+/// `batch_get` call, dataloader style.
+///
+/// Lookups of the same name in a batch share its result. Each principal gets
+/// its own batches, so the batch call is made on behalf of the principal of
+/// every lookup it serves. It runs under the logid of the request that opened
+/// the batch and the latest deadline among the requests waiting on it.
+pub struct Loader<T> {
+    client: ResourceClient<T>,
+    config: LoaderConfig,
+    batches: Mutex<Batches<T>>,
+}
+
+impl<T> Loader<T>
+where
+    T: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn new(client: ResourceClient<T>, config: LoaderConfig) -> Self {
+        Self {
+            client,
+            config,
+            batches: Mutex::new(Batches {
+                next_epoch: 0,
+                pending: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Looks up `name`, `None` when the service doesn't have it.
+    pub async fn load(self: &Arc<Self>, name: &str) -> Result<Option<T>, StatusCode> {
+        let (sender, receiver) = oneshot::channel();
+        let principal = current_principal();
+
+        {
+            let mut batches = self.batches.lock().unwrap();
+            let batches = &mut *batches;
+
+            let batch = match batches.pending.entry(principal.clone()) {
+                Entry::Occupied(entry) => {
+                    let batch = entry.into_mut();
+
+                    batch.deadline = match (batch.deadline, current_deadline()) {
+                        (Some(ours), Some(theirs)) => Some(ours.max(theirs)),
+                        _ => None,
+                    };
+
+                    batch
+                }
+                Entry::Vacant(entry) => {
+                    let epoch = batches.next_epoch;
+                    batches.next_epoch += 1;
+
+                    let loader = self.clone();
+                    let principal = principal.clone();
+
+                    tokio::spawn(async move {
+                        tokio::time::sleep(loader.config.window).await;
+                        loader.flush(principal, Some(epoch));
+                    });
+
+                    entry.insert(Batch {
+                        epoch,
+                        waiters: HashMap::new(),
+                        logid: current_logid(),
+                        deadline: current_deadline(),
+                    })
+                }
+            };
+
+            batch.waiters.entry(name.to_string()).or_default().push(sender);
+
+            if batch.waiters.len() >= self.config.max_batch {
+                self.flush_locked(batches, principal, None);
+            }
+        }
+
+        receiver.await.unwrap_or_else(|_| {
+            error!("batch dropped without a result: name={}", name);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        })
+    }
+
+    /// Sends `principal`'s pending batch, unless `epoch` is given and it has
+    /// already been sent.
+    fn flush(&self, principal: Option<String>, epoch: Option<u64>) {
+        let mut batches = self.batches.lock().unwrap();
+
+        self.flush_locked(&mut batches, principal, epoch);
+    }
+
+    fn flush_locked(&self, batches: &mut Batches<T>, principal: Option<String>, epoch: Option<u64>) {
+        let (principal, Batch { waiters, logid, deadline, .. }) = match batches.pending.entry(principal) {
+            Entry::Occupied(entry) if epoch.is_none_or(|epoch| epoch == entry.get().epoch) => entry.remove_entry(),
+            _ => return,
+        };
+
+        let client = self.client.clone();
+        let logid = logid.unwrap_or_else(generate_trace_id);
+
+        tokio::spawn(scope_logid(logid, scope_principal(principal, async move {
+            let names: Vec<String> = waiters.keys().cloned().collect();
+
+            info!("loading batch: names={}", names.len());
+
+            let result = match deadline {
+                Some(deadline) => scope_deadline(deadline, client.batch_get(&names)).await,
+                None => client.batch_get(&names).await,
+            };
+
+            match result {
+                Ok(found) => {
+                    let mut found: HashMap<String, T> =
+                        found.items.into_iter().map(|item| (item.name, item.value)).collect();
+
+                    for (name, senders) in waiters {
+                        let value = found.remove(&name);
+
+                        for sender in senders {
+                            let _ = sender.send(Ok(value.clone()));
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("error loading batch: names={}, error={}", names.len(), e);
+
+                    let status = StatusCode::from(&e);
+
+                    for sender in waiters.into_values().flatten() {
+                        let _ = sender.send(Err(status));
+                    }
+                }
+            }
+        })));
+    }
+}
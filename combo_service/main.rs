@@ -1,12 +1,31 @@
-use std::{fmt::Display, fmt::Formatter, sync::Arc, time::Duration};
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Error;
-use axum::Router;
+use axum::{middleware, Router};
 use reqwest::Client;
-use shared::{init::{start_server, init_tracing}, util_router, layer::{tracing_layer, logid_layer}};
+use shared::{
+    breaker::{BreakerConfig, CircuitBreaker},
+    client::{EntityClient, PropertyClient},
+    init::init_tracing,
+    layer::{logid_layer, logid_scope, principal_scope, tracing_layer},
+    registry::ServiceRegistry,
+    retry::{RetryConfig, RetryPolicy},
+    util_router,
+};
 use tracing::info;
 
+use cache::ComboCache;
+use loader::{Loader, LoaderConfig};
+use outbox::Outbox;
+use state::{combo_deadline, ComboState, UpstreamTimeout};
+
 mod biz_router;
+mod cache;
+mod loader;
+mod outbox;
+mod saga;
+mod state;
+mod util;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -16,17 +35,67 @@ async fn main() -> Result<(), Error> {
 
     info!("Creating client pool");
 
-    let app_state = Arc::new(
+    let client = Arc::new(
         Client::builder()
         .connect_timeout(Duration::from_millis(1000))
         .build()?
     );
 
+    info!("Creating outbox");
+
+    let timeout = UpstreamTimeout::from_env();
+
+    let breaker_config = BreakerConfig::from_env();
+    let retry_config = RetryConfig::from_env();
+
+    let registry = Arc::new(ServiceRegistry::from_env());
+    registry.spawn_refresher();
+
+    // Each upstream gets its own breaker and retry budget.
+    let entities = EntityClient::from_registry(client.clone(), registry.clone())
+        .with_breaker(Arc::new(CircuitBreaker::new("entity", breaker_config)))
+        .with_retry(Arc::new(RetryPolicy::new(retry_config)));
+    let properties = PropertyClient::from_registry(client, registry)
+        .with_breaker(Arc::new(CircuitBreaker::new("property", breaker_config)))
+        .with_retry(Arc::new(RetryPolicy::new(retry_config)));
+
+    let outbox = Arc::new(Outbox::from_env(entities.clone(), properties.clone(), timeout)?);
+    outbox.spawn_sweeper();
+
+    info!("Creating loaders");
+
+    let loader_config = LoaderConfig::from_env();
+
+    let entity_loader = Arc::new(Loader::new(entities.clone(), loader_config));
+    let property_loader = Arc::new(Loader::new(properties.clone(), loader_config));
+
+    info!("Creating cache");
+
+    let cache = Arc::new(ComboCache::from_env());
+
+    if std::env::var("COMBO_CACHE_WATCH").is_ok_and(|watch| watch == "true") {
+        cache.spawn_invalidator(entities.clone(), "entity");
+        cache.spawn_invalidator(properties.clone(), "property");
+    }
+
+    let app_state = ComboState {
+        entities,
+        properties,
+        entity_loader,
+        property_loader,
+        outbox,
+        cache,
+        timeout,
+    };
+
     info!("Creating routers");
 
     let router = Router::new()
         .merge(util_router::get_router())
         .merge(biz_router::get_router())
+        .layer(middleware::from_fn_with_state(app_state.clone(), combo_deadline))
+        .layer(middleware::from_fn(principal_scope))
+        .layer(middleware::from_fn(logid_scope))
         .layer(tracing_layer())
         .layer(logid_layer())
         .with_state(app_state.clone());
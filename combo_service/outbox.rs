@@ -0,0 +1,309 @@
+use std::{
+    collections::HashSet,
+    fmt::{Display, Formatter},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use shared::{
+    client::{EntityClient, PropertyClient},
+    context::{current_principal, scope_deadline, scope_logid, scope_principal},
+    prelude::*,
+    state::{
+        entity::Entity,
+        property::Property,
+        query::Filterable,
+        storage::StorageError,
+        version::Precondition,
+        StateError,
+    },
+    trace::generate_trace_id,
+};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::state::UpstreamTimeout;
+
+const DEFAULT_SWEEP_INTERVAL_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Pending,
+    Compensating,
+}
+
+/// A combo write that has been accepted but not yet fully applied upstream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub name: String,
+    pub logid: String,
+    pub entity: Entity,
+    pub property: Property,
+    /// What the write replaces, put back if it's compensated; `None` when it
+    /// creates the entity or property.
+    #[serde(default)]
+    pub previous_entity: Option<Entity>,
+    #[serde(default)]
+    pub previous_property: Option<Property>,
+    pub stage: Stage,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl Display for OutboxEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "name={}, logid={}, stage={:?}, error={}",
+            self.name,
+            self.logid,
+            self.stage,
+            self.error.as_deref().unwrap_or("None")
+        )
+    }
+}
+
+impl Filterable for OutboxEntry {
+    const FIELDS: &'static [&'static str] = &[];
+
+    fn field(&self, _: &str) -> Option<&str> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    /// Both the entity and the property were written.
+    Completed,
+    /// The write failed and whatever it replaced was put back.
+    Compensated(String),
+    /// The write failed and compensating also failed; the sweeper will
+    /// keep retrying it.
+    Pending(String),
+}
+
+/// Records combo writes before sending them upstream, and drives each one to
+/// completion or compensates it, so a combo is either fully written or left
+/// as it was.
+///
+/// Entries are kept in an `AppState`, so with a durable backend they survive
+/// a restart and are picked up again by the sweeper.
+pub struct Outbox {
+    entries: AppState<OutboxEntry>,
+    entities: EntityClient,
+    properties: PropertyClient,
+    in_flight: Arc<Mutex<HashSet<String>>>,
+    timeout: UpstreamTimeout,
+}
+
+/// An entry being driven by one task; released when dropped, so a task that
+/// is cancelled or panics doesn't keep the sweeper away from it.
+struct Claim {
+    in_flight: Arc<Mutex<HashSet<String>>>,
+    key: String,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl Outbox {
+    /// Deletes are retried by the clients' own `RetryPolicy`; posts aren't, so a
+    /// failed one is compensated.
+    pub fn new(entries: AppState<OutboxEntry>, entities: EntityClient, properties: PropertyClient, timeout: UpstreamTimeout) -> Self {
+        Self {
+            entries,
+            entities,
+            properties,
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+            timeout,
+        }
+    }
+
+    /// Uses the `STATE_*` storage settings.
+    pub fn from_env(entities: EntityClient, properties: PropertyClient, timeout: UpstreamTimeout) -> Result<Self, StorageError> {
+        Ok(Self::new(AppState::from_env()?, entities, properties, timeout))
+    }
+
+    /// Records the write and drives it until it completes or is compensated,
+    /// compensating by restoring `previous`, the entity and property as they
+    /// were before.
+    ///
+    /// The writes themselves are abandoned at `deadline`. Compensating gets
+    /// its own `UpstreamTimeout`, and the entry is driven on its own task, so
+    /// it carries on if the request is cancelled; the request then gets
+    /// `Pending` and the sweeper reports how it ended.
+    pub async fn submit(
+        self: &Arc<Self>,
+        name: &str,
+        logid: &str,
+        (entity, property): (Entity, Property),
+        (previous_entity, previous_property): (Option<Entity>, Option<Property>),
+        deadline: Instant,
+    ) -> Result<Outcome, StateError> {
+        let key = format!("{name}/{}", generate_trace_id());
+
+        let entry = OutboxEntry {
+            name: name.to_string(),
+            logid: logid.to_string(),
+            entity,
+            property,
+            previous_entity,
+            previous_property,
+            stage: Stage::Pending,
+            error: None,
+        };
+
+        // Claimed before it's recorded, so the sweeper can't pick it up and
+        // drive it alongside this request.
+        let claim = match self.claim(&key) {
+            Some(claim) => claim,
+            None => {
+                warn!("outbox entry already in flight: key={key}");
+                return Ok(Outcome::Pending("already in flight".to_string()));
+            }
+        };
+
+        let outbox = self.clone();
+
+        let task = tokio::spawn(scope_logid(
+            logid.to_string(),
+            scope_principal(current_principal(), async move {
+                let _claim = claim;
+
+                outbox.entries.insert_if_absent(&key, &entry).await?;
+                outbox.drive(&key, entry, deadline).await
+            }),
+        ));
+
+        match tokio::time::timeout_at(deadline, task).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(e)) => {
+                error!("outbox task failed: name={name}, error={e}");
+                Ok(Outcome::Pending(e.to_string()))
+            }
+            Err(_) => Ok(Outcome::Pending("deadline exceeded, still in flight".to_string())),
+        }
+    }
+
+    /// Periodically picks up entries no request is driving, such as those
+    /// left over from a restart or a failed compensation.
+    pub fn spawn_sweeper(self: &Arc<Self>) {
+        let interval = std::env::var("OUTBOX_SWEEP_INTERVAL_MS")
+            .ok()
+            .and_then(|interval| interval.parse().ok())
+            .unwrap_or(DEFAULT_SWEEP_INTERVAL_MS);
+
+        let outbox = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_millis(interval));
+
+            loop {
+                ticker.tick().await;
+                outbox.sweep().await;
+            }
+        });
+    }
+
+    async fn sweep(&self) {
+        for item in self.entries.entries().await {
+            let Some(_claim) = self.claim(&item.name) else {
+                continue;
+            };
+
+            info!("resuming outbox entry: key={}, entry={}", item.name, item.value);
+
+            let logid = item.value.logid.clone();
+
+            match scope_logid(logid, self.drive(&item.name, item.value, self.timeout.deadline())).await {
+                Ok(outcome) => info!("resumed outbox entry: key={}, outcome={:?}", item.name, outcome),
+                Err(e) => error!("error resuming outbox entry: key={}, error={e}", item.name),
+            }
+        }
+    }
+
+    fn claim(&self, key: &str) -> Option<Claim> {
+        self.in_flight.lock().unwrap().insert(key.to_string()).then(|| Claim {
+            in_flight: self.in_flight.clone(),
+            key: key.to_string(),
+        })
+    }
+
+    async fn drive(&self, key: &str, mut entry: OutboxEntry, deadline: Instant) -> Result<Outcome, StateError> {
+        loop {
+            match entry.stage {
+                Stage::Pending => {
+                    info!("sending combo post: name={}", entry.name);
+
+                    let written = scope_deadline(deadline, tokio::time::timeout_at(deadline, async {
+                        tokio::try_join!(
+                            self.entities.set(&entry.name, &entry.entity),
+                            self.properties.set(&entry.name, &entry.property),
+                        )
+                        .map_err(|e| e.to_string())
+                    }))
+                    .await
+                    .unwrap_or_else(|_| Err("deadline exceeded".to_string()));
+
+                    entry = match written {
+                        Ok(_) => {
+                            self.entries.rm(key, &Precondition::none()).await?;
+                            return Ok(Outcome::Completed);
+                        }
+                        // Either post may still have landed, so undo both.
+                        Err(e) => self.advance(key, entry, Stage::Compensating, Some(e)).await?,
+                    };
+                }
+                Stage::Compensating => {
+                    warn!("compensating combo write: entry={}", entry);
+
+                    let reason = entry.error.clone().unwrap_or_default();
+
+                    // Not bound by the deadline the write missed.
+                    let (entity, property) = scope_deadline(Instant::now() + self.timeout.0, async {
+                        tokio::join!(
+                            async {
+                                match &entry.previous_entity {
+                                    Some(entity) => self.entities.set(&entry.name, entity).await,
+                                    None => self.entities.delete(&entry.name).await,
+                                }
+                            },
+                            async {
+                                match &entry.previous_property {
+                                    Some(property) => self.properties.set(&entry.name, property).await,
+                                    None => self.properties.delete(&entry.name).await,
+                                }
+                            },
+                        )
+                    })
+                    .await;
+
+                    return match entity.and(property) {
+                        Ok(()) => {
+                            self.entries.rm(key, &Precondition::none()).await?;
+                            Ok(Outcome::Compensated(reason))
+                        }
+                        Err(e) => {
+                            error!("error compensating combo write, leaving in outbox: entry={}, error={e}", entry);
+                            Ok(Outcome::Pending(reason))
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    async fn advance(&self, key: &str, mut entry: OutboxEntry, stage: Stage, error: Option<String>) -> Result<OutboxEntry, StateError> {
+        entry.stage = stage;
+        entry.error = error.or(entry.error);
+
+        self.entries.set(key, &entry, &Precondition::none()).await?;
+
+        Ok(entry)
+    }
+}
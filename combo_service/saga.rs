@@ -0,0 +1,272 @@
+use std::{future::Future, pin::Pin, time::Duration};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use serde::Serialize;
+use shared::{
+    client::ClientError,
+    context::{current_logid, current_principal, scope_deadline, scope_logid, scope_principal},
+    trace::generate_trace_id,
+};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+type ActionFuture = Pin<Box<dyn Future<Output = Result<Option<u64>, ClientError>> + Send>>;
+type ActionFn = Box<dyn FnOnce() -> ActionFuture + Send>;
+type StepFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+type StepFn = Box<dyn FnOnce(Option<u64>) -> StepFuture + Send>;
+
+struct Step {
+    name: &'static str,
+    action: ActionFn,
+    compensation: StepFn,
+}
+
+/// Runs a set of upstream writes, undoing the ones that may have landed when
+/// another fails.
+///
+/// Each action returns the version it wrote, if the upstream said, and its
+/// compensation is handed that version so it can undo the write only if it
+/// is still the latest.
+pub struct Saga {
+    steps: Vec<Step>,
+    compensation_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompensationFailure {
+    pub step: &'static str,
+    pub error: String,
+}
+
+/// Reported to the caller when a saga fails.
+#[derive(Debug, Clone, Serialize)]
+pub struct SagaError {
+    pub failed_step: &'static str,
+    pub error: String,
+    pub compensated: Vec<&'static str>,
+    pub compensation_failures: Vec<CompensationFailure>,
+}
+
+impl Saga {
+    /// Compensating gets `compensation_timeout` of its own, as the deadline
+    /// the steps ran under may be what they failed on.
+    pub fn new(compensation_timeout: Duration) -> Self {
+        Self {
+            steps: Vec::new(),
+            compensation_timeout,
+        }
+    }
+
+    pub fn step<A, AF, C, CF>(mut self, name: &'static str, action: A, compensation: C) -> Self
+    where
+        A: FnOnce() -> AF + Send + 'static,
+        AF: Future<Output = Result<Option<u64>, ClientError>> + Send + 'static,
+        C: FnOnce(Option<u64>) -> CF + Send + 'static,
+        CF: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.steps.push(Step {
+            name,
+            action: Box::new(move || Box::pin(action())),
+            compensation: Box::new(move |written| Box::pin(compensation(written))),
+        });
+
+        self
+    }
+
+    /// Runs every step at once, giving up at `deadline`.
+    ///
+    /// The first failure drops the steps still running. Since a dropped or
+    /// timed out write may already have landed, every step is compensated
+    /// except one whose failure shows it wasn't applied, such as a rejection.
+    /// Compensations run on a task of their own, so they carry on if the
+    /// caller is cancelled.
+    pub async fn run(self, deadline: Instant) -> Result<(), SagaError> {
+        let compensation_timeout = self.compensation_timeout;
+
+        let mut names = Vec::with_capacity(self.steps.len());
+        let mut compensations = Vec::with_capacity(self.steps.len());
+        let mut running = FuturesUnordered::new();
+
+        for (index, step) in self.steps.into_iter().enumerate() {
+            info!("running saga step: step={}", step.name);
+
+            names.push(step.name);
+            compensations.push(Some(step.compensation));
+
+            let action = step.action;
+            running.push(async move { (index, action().await) });
+        }
+
+        let mut finished = vec![false; names.len()];
+        let mut written = vec![None; names.len()];
+
+        let (failed, error) = loop {
+            match tokio::time::timeout_at(deadline, running.next()).await {
+                Ok(Some((index, Ok(version)))) => {
+                    finished[index] = true;
+                    written[index] = version;
+                }
+                Ok(Some((index, Err(error)))) => {
+                    if !error.may_have_applied() {
+                        compensations[index] = None;
+                    }
+
+                    break (index, error.to_string());
+                }
+                Ok(None) => return Ok(()),
+                Err(_) => {
+                    let index = finished.iter().position(|finished| !finished).unwrap_or_default();
+                    break (index, "deadline exceeded".to_string());
+                }
+            }
+        };
+
+        drop(running);
+
+        error!("saga step failed: step={}, error={}", names[failed], error);
+
+        let completed = names
+            .iter()
+            .zip(compensations)
+            .zip(written)
+            .filter_map(|((name, compensation), written)| compensation.map(|compensation| (*name, compensation, written)))
+            .collect();
+
+        let failed_step = names[failed];
+
+        let compensating = tokio::spawn(scope_logid(
+            current_logid().unwrap_or_else(generate_trace_id),
+            scope_principal(
+                current_principal(),
+                scope_deadline(Instant::now() + compensation_timeout, Self::compensate(failed_step, error.clone(), completed)),
+            ),
+        ));
+
+        Err(compensating.await.unwrap_or_else(|e| SagaError {
+            failed_step,
+            error,
+            compensated: Vec::new(),
+            compensation_failures: vec![CompensationFailure {
+                step: failed_step,
+                error: format!("compensation task failed: {e}"),
+            }],
+        }))
+    }
+
+    async fn compensate(
+        failed_step: &'static str,
+        error: String,
+        completed: Vec<(&'static str, StepFn, Option<u64>)>,
+    ) -> SagaError {
+        let mut compensated = Vec::new();
+        let mut compensation_failures = Vec::new();
+
+        for (name, compensation, written) in completed.into_iter().rev() {
+            warn!("compensating saga step: step={}, written={:?}", name, written);
+
+            match compensation(written).await {
+                Ok(()) => compensated.push(name),
+                Err(error) => {
+                    error!("saga compensation failed: step={}, error={}", name, error);
+                    compensation_failures.push(CompensationFailure { step: name, error });
+                }
+            }
+        }
+
+        SagaError {
+            failed_step,
+            error,
+            compensated,
+            compensation_failures,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    type Log = Arc<Mutex<Vec<(&'static str, Option<u64>)>>>;
+
+    fn saga() -> Saga {
+        Saga::new(Duration::from_secs(5))
+    }
+
+    fn step(saga: Saga, log: &Log, name: &'static str, result: Result<Option<u64>, ClientError>) -> Saga {
+        let log = log.clone();
+
+        saga.step(name, move || async move { result }, move |written| async move {
+            log.lock().unwrap().push((name, written));
+            Ok(())
+        })
+    }
+
+    async fn compensated(failure: ClientError) -> Vec<(&'static str, Option<u64>)> {
+        let log = Log::default();
+
+        let saga = step(saga(), &log, "ok", Ok(Some(7)));
+        let saga = step(saga, &log, "failed", Err(failure));
+
+        let error = saga.run(Instant::now() + Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(error.failed_step, "failed");
+
+        let mut log = log.lock().unwrap().clone();
+        log.sort();
+        log
+    }
+
+    #[tokio::test]
+    async fn rejected_step_is_not_compensated() {
+        let rejected = ClientError::Rejected(reqwest::StatusCode::BAD_REQUEST);
+
+        assert_eq!(compensated(rejected).await, vec![("ok", Some(7))]);
+    }
+
+    #[tokio::test]
+    async fn ambiguous_failure_is_compensated_without_a_version() {
+        let failed = ClientError::Upstream(reqwest::StatusCode::BAD_GATEWAY);
+
+        assert_eq!(compensated(failed).await, vec![("failed", None), ("ok", Some(7))]);
+        assert_eq!(compensated(ClientError::DeadlineExceeded).await, vec![("failed", None), ("ok", Some(7))]);
+    }
+
+    #[tokio::test]
+    async fn succeeds_without_compensating() {
+        let log = Log::default();
+
+        let saga = step(saga(), &log, "a", Ok(None));
+        let saga = step(saga, &log, "b", Ok(None));
+
+        assert!(saga.run(Instant::now() + Duration::from_secs(5)).await.is_ok());
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn compensates_past_the_deadline_and_the_caller() {
+        let log = Log::default();
+
+        // A step that never answers, so the saga fails at its deadline.
+        let saga = step(saga(), &log, "ok", Ok(Some(1))).step("hung", std::future::pending, |_| async { Ok(()) });
+
+        // Still compensating once the caller has given up too.
+        let slow_log = log.clone();
+        let saga = saga.step("slow", || async { Ok(Some(2)) }, move |written| async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            slow_log.lock().unwrap().push(("slow", written));
+            Ok(())
+        });
+
+        let run = saga.run(Instant::now() + Duration::from_millis(10));
+
+        assert!(tokio::time::timeout(Duration::from_millis(20), run).await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut log = log.lock().unwrap().clone();
+        log.sort();
+
+        assert_eq!(log, vec![("ok", Some(1)), ("slow", Some(2))]);
+    }
+}
@@ -0,0 +1,66 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{FromRef, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use shared::{
+    client::{EntityClient, PropertyClient},
+    context::current_deadline,
+    header_helper::get_deadline_blocking,
+    layer::enforce_deadline,
+    state::{entity::Entity, property::Property},
+};
+use tokio::time::Instant;
+
+use crate::{cache::ComboCache, loader::Loader, outbox::Outbox};
+
+const DEFAULT_UPSTREAM_TIMEOUT_MS: u64 = 2000;
+
+pub type EntityLoader = Arc<Loader<Entity>>;
+pub type PropertyLoader = Arc<Loader<Property>>;
+
+#[derive(Clone, FromRef)]
+pub struct ComboState {
+    pub entities: EntityClient,
+    pub properties: PropertyClient,
+    pub entity_loader: EntityLoader,
+    pub property_loader: PropertyLoader,
+    pub outbox: Arc<Outbox>,
+    pub cache: Arc<ComboCache>,
+    pub timeout: UpstreamTimeout,
+}
+
+/// How long a combo request may spend waiting on entity_microservice and
+/// property_microservice, shared by all of its upstream calls.
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamTimeout(pub Duration);
+
+impl UpstreamTimeout {
+    /// Reads `UPSTREAM_TIMEOUT_MS`.
+    pub fn from_env() -> Self {
+        let timeout = std::env::var("UPSTREAM_TIMEOUT_MS")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(DEFAULT_UPSTREAM_TIMEOUT_MS);
+
+        Self(Duration::from_millis(timeout))
+    }
+
+    /// The sooner of our own timeout and the current request's deadline.
+    pub fn deadline(&self) -> Instant {
+        let own = Instant::now() + self.0;
+
+        current_deadline().map_or(own, |deadline| deadline.min(own))
+    }
+}
+
+/// Like `shared::layer::deadline_scope`, but never allows more than the
+/// upstream timeout, so entity/property calls are told how long we will
+/// actually wait for them.
+pub async fn combo_deadline(State(timeout): State<UpstreamTimeout>, request: Request, next: Next) -> Response {
+    let budget = get_deadline_blocking(request.headers()).map_or(timeout.0, |budget| budget.min(timeout.0));
+
+    enforce_deadline(Instant::now() + budget, request, next).await
+}
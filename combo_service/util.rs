@@ -0,0 +1,33 @@
+use std::future::Future;
+
+use axum::http::StatusCode;
+use tokio::time::Instant;
+use tracing::error;
+
+/// Runs `call` until `deadline`, failing with 504 once it passes.
+pub async fn within<F, T, E>(deadline: Instant, call: F) -> Result<T, StatusCode>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<StatusCode>,
+{
+    match tokio::time::timeout_at(deadline, call).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => {
+            error!("upstream deadline exceeded");
+            Err(StatusCode::GATEWAY_TIMEOUT)
+        }
+    }
+}
+
+/// Runs the entity and property calls concurrently under one deadline.
+///
+/// The first failure is returned straight away, dropping the sibling call and
+/// with it any request it still has in flight.
+pub async fn fan_out<E, P, EF, PF, Err>(deadline: Instant, entity: EF, property: PF) -> Result<(E, P), StatusCode>
+where
+    EF: Future<Output = Result<E, Err>>,
+    PF: Future<Output = Result<P, Err>>,
+    Err: Into<StatusCode>,
+{
+    within(deadline, async { tokio::try_join!(entity, property) }).await
+}
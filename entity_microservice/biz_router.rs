@@ -1,7 +1,7 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use axum::{
-    extract::{Json, Path, State},
+    extract::{Json, Path, Query, State},
     http::{StatusCode, HeaderMap},
     response::IntoResponse,
     Router,
@@ -9,20 +9,82 @@ use axum::{
 };
 use shared::{
     prelude::*,
-    header_helper::get_logid,
-    state::entity::{Entity, PartialEntity}
+    change_feed::{change_stream, WatchQuery},
+    header_helper::set_header_etag,
+    state::{
+        entity::{Entity, PartialEntity},
+        query::{BatchGet, ListQuery},
+        version::Precondition,
+    },
 };
 
-use tracing::{error, info, span, Level};
+use tracing::{error, info};
 
 pub fn get_router() -> Router<Arc<AppState<Entity>>> {
     Router::new()
+        .route("/entity", get(list_entity))
+        // matchit has no way to escape ':', so "/entity:batchGet" routes as
+        // "/entity" followed by a parameter holding ":batchGet".
+        .route("/entity:method", post(entity_method))
+        // Static segments win over ":name", so "watch" names no item.
+        .route("/entity/watch", get(watch_entity))
         .route("/entity/:name", get(get_entity))
         .route("/entity/:name", delete(delete_entity))
         .route("/entity/:name", post(post_entity))
         .route("/entity/:name", patch(patch_entity))
 }
 
+async fn list_entity(
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<Arc<AppState<Entity>>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    info!("req: params={:?}", params);
+
+    let query = ListQuery::from_params::<Entity>(params)?;
+
+    let page = state.list(&query).await;
+
+    info!("resp: items={}, next_cursor={:?}", page.items.len(), page.next_cursor);
+
+    Ok(Json(page))
+}
+
+async fn entity_method(
+    Path(method): Path<String>,
+    State(state): State<Arc<AppState<Entity>>>,
+    Json(batch): Json<BatchGet>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if method != ":batchGet" {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    info!("req: names={}", batch.names.len());
+
+    batch.validate()?;
+
+    let result = state.get_many(&batch.names).await;
+
+    info!("resp: items={}, missing={}", result.items.len(), result.missing.len());
+
+    Ok(Json(result))
+}
+
+async fn watch_entity(
+    headers: HeaderMap,
+    Query(query): Query<WatchQuery>,
+    State(state): State<Arc<AppState<Entity>>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let since = query.resume_from(&headers);
+
+    info!("req: since={:?}, prefix={:?}", since, query.prefix);
+
+    let (backlog, receiver) = state.watch(since).await?;
+
+    info!("resp: backlog={}", backlog.len());
+
+    Ok(change_stream(backlog, receiver, query.prefix))
+}
+
 async fn get_entity(
     headers: HeaderMap,
     Path(name): Path<String>,
@@ -33,7 +95,15 @@ async fn get_entity(
     match state.get(&name).await {
         Some(value) => {
             info!("resp: value={}", value);
-            Ok(Json(value))
+
+            let mut response = match Precondition::from_headers(&headers).not_modified(value.version) {
+                true => StatusCode::NOT_MODIFIED.into_response(),
+                false => Json(&value.value).into_response(),
+            };
+
+            set_header_etag(response.headers_mut(), &value.etag());
+
+            Ok(response)
         }
         None => {
             error!("resp: status={}", StatusCode::NOT_FOUND);
@@ -50,9 +120,17 @@ async fn post_entity(
 ) -> Result<impl IntoResponse, StatusCode> {
     info!("req: payload={:?}", payload);
 
-    state.set(name.as_str(), &payload).await;
+    let precondition = Precondition::from_headers(&headers);
+
+    let stored = match precondition.requires_absent() {
+        true => state.insert_if_absent(name.as_str(), &payload).await?,
+        false => state.set(name.as_str(), &payload, &precondition).await?,
+    };
 
-    Ok(StatusCode::CREATED.into_response())
+    let mut response = StatusCode::CREATED.into_response();
+    set_header_etag(response.headers_mut(), &stored.etag());
+
+    Ok(response)
 }
 
 async fn patch_entity(
@@ -63,11 +141,33 @@ async fn patch_entity(
 ) -> Result<impl IntoResponse, StatusCode> {
     info!("req: payload={:?}", payload);
 
-    match state.get(&name).await {
-        Some(value) => {
-            let merged = payload.merge(&value);
-            state.set(name.as_str(), &merged).await;
-            Ok(StatusCode::NO_CONTENT.into_response())
+    let precondition = Precondition::from_headers(&headers);
+
+    let stored = match precondition.is_none() {
+        true => {
+            state.update_with(&name, |current| current.map(|value| payload.merge(value))).await?
+        }
+        false => {
+            let current = state.get(&name).await;
+
+            precondition.evaluate(current.as_ref().map(|value| value.version))?;
+
+            match current {
+                Some(current) => {
+                    let merged = payload.merge(&current.value);
+                    Some(state.compare_and_swap(&name, Some(current.version), &merged).await?)
+                }
+                None => None,
+            }
+        }
+    };
+
+    match stored {
+        Some(stored) => {
+            let mut response = StatusCode::NO_CONTENT.into_response();
+            set_header_etag(response.headers_mut(), &stored.etag());
+
+            Ok(response)
         }
         None => {
             error!("resp: status={}", StatusCode::NOT_FOUND);
@@ -83,7 +183,9 @@ async fn delete_entity(
 ) -> Result<impl IntoResponse, StatusCode> {
     info!("req: name={}", name);
 
-    state.rm(&name).await;
+    let precondition = Precondition::from_headers(&headers);
+
+    state.rm(&name, &precondition).await?;
 
     Ok(StatusCode::NO_CONTENT.into_response())
-}
\ No newline at end of file
+}
@@ -1,9 +1,22 @@
 use anyhow::Error;
-use shared::init::start_server;
+use shared::{events::{spawn_publisher, KafkaSink}, init::{start_server, init_tracing}, prelude::AppState, state::entity::Entity};
+use tracing::info;
 
 mod biz_router;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    start_server(biz_router::get_router()).await
+    init_tracing();
+
+    info!("Creating app_state");
+
+    let app_state = AppState::<Entity>::from_env()?
+        .with_index("colour", |entity| entity.colour().to_string())
+        .with_index("origin", |entity| entity.origin().to_string());
+
+    if let Some(sink) = KafkaSink::from_env("entity_changed")? {
+        spawn_publisher(app_state.clone(), sink, "EntityChanged").await?;
+    }
+
+    start_server(biz_router::get_router(), app_state).await
 }
@@ -2,10 +2,10 @@ use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use rdkafka::{
     config::FromClientConfig,
-    producer::{BaseRecord, FutureProducer, FutureRecord},
+    producer::{FutureProducer, FutureRecord},
     ClientConfig,
 };
-use tokio::io::{stdin, AsyncBufRead, AsyncBufReadExt, AsyncRead, BufReader, Lines};
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
 
 async fn handle_line(
     line: &String,
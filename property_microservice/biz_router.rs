@@ -1,7 +1,7 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use axum::{
-    extract::{Json, Path, State},
+    extract::{Json, Path, Query, State},
     http::{StatusCode, HeaderMap},
     response::IntoResponse,
     Router,
@@ -9,19 +9,82 @@ use axum::{
 };
 use shared::{
     prelude::*,
-    state::property::{Property, PartialProperty}, header_helper::get_logid
+    change_feed::{change_stream, WatchQuery},
+    header_helper::set_header_etag,
+    state::{
+        property::{Property, PartialProperty},
+        query::{BatchGet, ListQuery},
+        version::Precondition,
+    },
 };
 
-use tracing::{error, info, span, Level};
+use tracing::{error, info};
 
 pub fn get_router() -> Router<Arc<AppState<Property>>> {
     Router::new()
+        .route("/property", get(list_property))
+        // matchit has no way to escape ':', so "/property:batchGet" routes as
+        // "/property" followed by a parameter holding ":batchGet".
+        .route("/property:method", post(property_method))
+        // Static segments win over ":name", so "watch" names no item.
+        .route("/property/watch", get(watch_property))
         .route("/property/:name", get(get_property))
         .route("/property/:name", delete(delete_property))
         .route("/property/:name", post(post_property))
         .route("/property/:name", patch(patch_property))
 }
 
+async fn list_property(
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<Arc<AppState<Property>>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    info!("req: params={:?}", params);
+
+    let query = ListQuery::from_params::<Property>(params)?;
+
+    let page = state.list(&query).await;
+
+    info!("resp: items={}, next_cursor={:?}", page.items.len(), page.next_cursor);
+
+    Ok(Json(page))
+}
+
+async fn property_method(
+    Path(method): Path<String>,
+    State(state): State<Arc<AppState<Property>>>,
+    Json(batch): Json<BatchGet>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if method != ":batchGet" {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    info!("req: names={}", batch.names.len());
+
+    batch.validate()?;
+
+    let result = state.get_many(&batch.names).await;
+
+    info!("resp: items={}, missing={}", result.items.len(), result.missing.len());
+
+    Ok(Json(result))
+}
+
+async fn watch_property(
+    headers: HeaderMap,
+    Query(query): Query<WatchQuery>,
+    State(state): State<Arc<AppState<Property>>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let since = query.resume_from(&headers);
+
+    info!("req: since={:?}, prefix={:?}", since, query.prefix);
+
+    let (backlog, receiver) = state.watch(since).await?;
+
+    info!("resp: backlog={}", backlog.len());
+
+    Ok(change_stream(backlog, receiver, query.prefix))
+}
+
 async fn get_property(
     headers: HeaderMap,
     Path(name): Path<String>,
@@ -32,7 +95,15 @@ async fn get_property(
     match state.get(&name).await {
         Some(value) => {
             info!("resp: value={}", value);
-            Ok(Json(value))
+
+            let mut response = match Precondition::from_headers(&headers).not_modified(value.version) {
+                true => StatusCode::NOT_MODIFIED.into_response(),
+                false => Json(&value.value).into_response(),
+            };
+
+            set_header_etag(response.headers_mut(), &value.etag());
+
+            Ok(response)
         }
         None => {
             error!("resp: status={}", StatusCode::NOT_FOUND);
@@ -49,9 +120,17 @@ async fn post_property(
 ) -> Result<impl IntoResponse, StatusCode> {
     info!("req: payload={:?}", payload);
 
-    state.set(name.as_str(), &payload).await;
+    let precondition = Precondition::from_headers(&headers);
+
+    let stored = match precondition.requires_absent() {
+        true => state.insert_if_absent(name.as_str(), &payload).await?,
+        false => state.set(name.as_str(), &payload, &precondition).await?,
+    };
 
-    Ok(StatusCode::CREATED.into_response())
+    let mut response = StatusCode::CREATED.into_response();
+    set_header_etag(response.headers_mut(), &stored.etag());
+
+    Ok(response)
 }
 
 async fn patch_property(
@@ -62,11 +141,33 @@ async fn patch_property(
 ) -> Result<impl IntoResponse, StatusCode> {
     info!("req: payload={:?}", payload);
 
-    match state.get(&name).await {
-        Some(value) => {
-            let merged = payload.merge(&value);
-            state.set(name.as_str(), &merged).await;
-            Ok(StatusCode::NO_CONTENT.into_response())
+    let precondition = Precondition::from_headers(&headers);
+
+    let stored = match precondition.is_none() {
+        true => {
+            state.update_with(&name, |current| current.map(|value| payload.merge(value))).await?
+        }
+        false => {
+            let current = state.get(&name).await;
+
+            precondition.evaluate(current.as_ref().map(|value| value.version))?;
+
+            match current {
+                Some(current) => {
+                    let merged = payload.merge(&current.value);
+                    Some(state.compare_and_swap(&name, Some(current.version), &merged).await?)
+                }
+                None => None,
+            }
+        }
+    };
+
+    match stored {
+        Some(stored) => {
+            let mut response = StatusCode::NO_CONTENT.into_response();
+            set_header_etag(response.headers_mut(), &stored.etag());
+
+            Ok(response)
         }
         None => {
             error!("resp: status={}", StatusCode::NOT_FOUND);
@@ -82,7 +183,9 @@ async fn delete_property(
 ) -> Result<impl IntoResponse, StatusCode> {
     info!("req: name={}", name);
 
-    state.rm(&name).await;
+    let precondition = Precondition::from_headers(&headers);
+
+    state.rm(&name, &precondition).await?;
 
     Ok(StatusCode::NO_CONTENT.into_response())
-}
\ No newline at end of file
+}
@@ -1,9 +1,20 @@
 use anyhow::Error;
-use shared::init::start_server;
+use shared::{events::{spawn_publisher, KafkaSink}, init::{start_server, init_tracing}, prelude::AppState, state::property::Property};
+use tracing::info;
 
 mod biz_router;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    start_server(biz_router::get_router()).await
+    init_tracing();
+
+    info!("Creating app_state");
+
+    let app_state = AppState::<Property>::from_env()?;
+
+    if let Some(sink) = KafkaSink::from_env("property_changed")? {
+        spawn_publisher(app_state.clone(), sink, "PropertyChanged").await?;
+    }
+
+    start_server(biz_router::get_router(), app_state).await
 }
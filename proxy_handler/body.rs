@@ -0,0 +1,160 @@
+use std::{
+    fmt::{Display, Formatter},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{header, HeaderMap},
+};
+use http_body::{Frame, SizeHint};
+use futures::StreamExt;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, warn};
+
+use crate::{error::ProxyError, pool::Lease};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Chunks read ahead of the upstream before the client is made to wait.
+const REQUEST_BUFFER_CHUNKS: usize = 8;
+
+/// Sent upstream in place of the rest of a request body once it passes the
+/// route's limit, aborting the request.
+#[derive(Debug)]
+pub struct BodyTooLarge {
+    pub limit: u64,
+}
+
+impl Display for BodyTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request body over limit: limit={}", self.limit)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// Rejects a request up front when its `Content-Length` is over `limit`.
+pub fn check_length(headers: &HeaderMap, limit: u64) -> Result<(), ProxyError> {
+    let length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+
+    match length {
+        Some(length) if length > limit => {
+            warn!("request body over limit: content_length={length}, limit={limit}");
+            Err(ProxyError::BodyTooLarge { limit })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Streams a request body upstream as it arrives, chunked or not, failing
+/// the upstream request with `BodyTooLarge` if it passes `limit`.
+pub fn stream_request(body: Body, limit: u64) -> reqwest::Body {
+    let (sender, receiver) = mpsc::channel::<Result<Bytes, BoxError>>(REQUEST_BUFFER_CHUNKS);
+
+    // reqwest wants a Sync stream, which axum's body isn't, so it is pumped
+    // through a channel.
+    tokio::spawn(async move {
+        let mut stream = body.into_data_stream();
+        let mut read = 0u64;
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    error!("error reading request body: error={}", e);
+                    let _ = sender.send(Err(e.into())).await;
+                    return;
+                }
+            };
+
+            read += chunk.len() as u64;
+
+            if read > limit {
+                warn!("request body over limit: read={read}, limit={limit}");
+                let _ = sender.send(Err(Box::new(BodyTooLarge { limit }))).await;
+                return;
+            }
+
+            // The upstream request has been dropped.
+            if sender.send(Ok(chunk)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    reqwest::Body::wrap_stream(ReceiverStream::new(receiver))
+}
+
+/// Reads the whole request body, for requests that may be sent more than
+/// once.
+pub async fn buffer_request(body: Body, limit: u64) -> Result<Bytes, ProxyError> {
+    match Limited::new(body, limit as usize).collect().await {
+        Ok(collected) => Ok(collected.to_bytes()),
+        Err(e) if e.downcast_ref::<LengthLimitError>().is_some() => {
+            warn!("request body over limit: limit={limit}");
+            Err(ProxyError::BodyTooLarge { limit })
+        }
+        Err(e) => {
+            error!("Error reading body: {}", e);
+            Err(ProxyError::BadRequest(e.to_string()))
+        }
+    }
+}
+
+/// Streams the upstream response body back as it arrives, holding `lease`
+/// until it is done so the endpoint counts as busy meanwhile.
+pub fn stream_response(res: reqwest::Response, lease: Lease) -> Body {
+    Body::from_stream(res.bytes_stream().map(move |chunk| {
+        let _lease = &lease;
+        chunk
+    }))
+}
+
+/// Keeps `held` alive until `body` is done, keeping its length.
+pub fn hold<T: Send + Unpin + 'static>(body: Body, held: T) -> Body {
+    Body::new(Held { body, _held: held })
+}
+
+struct Held<T> {
+    body: Body,
+    _held: T,
+}
+
+impl<T: Unpin> HttpBody for Held<T> {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// The limit a request body passed, if that is why `e` happened.
+pub fn too_large_limit(e: &reqwest::Error) -> Option<u64> {
+    let mut source = std::error::Error::source(e);
+
+    while let Some(e) = source {
+        if let Some(too_large) = e.downcast_ref::<BodyTooLarge>() {
+            return Some(too_large.limit);
+        }
+
+        source = e.source();
+    }
+
+    None
+}
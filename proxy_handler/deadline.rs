@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use shared::{context::scope_deadline, header_helper::get_logid_blocking};
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::{error::ProxyError, routes::RouteTable};
+
+/// Starts the request's deadline from its route's `deadline_ms`. The
+/// handlers pass what is left of it on to the backing service; if it passes
+/// first the proxy answers 504 itself.
+pub async fn route_deadline(State(routes): State<Arc<RouteTable>>, request: Request, next: Next) -> Response {
+    let host = request.headers().get(header::HOST).and_then(|host| host.to_str().ok());
+
+    let budget = routes.deadline(routes.find(request.method(), host, request.uri().path()));
+    let deadline = Instant::now() + budget;
+    let logid = get_logid_blocking(request.headers());
+
+    match scope_deadline(deadline, tokio::time::timeout_at(deadline, next.run(request))).await {
+        Ok(response) => response,
+        Err(_) => {
+            let e = ProxyError::DeadlineExceeded { budget };
+            error!("resp: status={}, error={}", e.status(), e);
+            e.into_response(&logid)
+        }
+    }
+}
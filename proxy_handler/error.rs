@@ -0,0 +1,200 @@
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use shared::header_helper::LOGID_HEADER;
+
+use crate::{body::too_large_limit, ratelimit::seconds};
+
+/// Shed requests are told to come back after this.
+const OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Why the proxy answered instead of the upstream.
+#[derive(Debug)]
+pub enum ProxyError {
+    NoRoute,
+    NoHealthyEndpoint { pool: String },
+    BodyTooLarge { limit: u64 },
+    BadRequest(String),
+    /// The request's route needs credentials it didn't have.
+    Unauthorized(String),
+    /// The client's rate limit bucket for the route is empty.
+    RateLimited { retry_after: Duration },
+    /// Too many requests already in flight through the proxy.
+    Overloaded,
+    /// The upstream couldn't be connected to.
+    UpstreamConnect { pool: String, endpoint: String, error: String },
+    /// Connecting to the upstream timed out.
+    UpstreamTimeout { pool: String, endpoint: String },
+    /// The route's deadline passed before an upstream answered, e.g. while
+    /// retrying.
+    DeadlineExceeded { budget: Duration },
+    /// The upstream connection failed after the request was sent, e.g. it
+    /// was reset or the response was malformed.
+    Upstream { pool: String, endpoint: String, error: String },
+    /// Our own failure, e.g. a request that couldn't be built.
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endpoint: Option<&'a str>,
+    logid: &'a str,
+}
+
+impl ProxyError {
+    /// Classifies a failure sending to `endpoint` in `pool`.
+    pub fn from_send(e: reqwest::Error, pool: &str, endpoint: &str) -> Self {
+        let (pool, endpoint) = (pool.to_string(), endpoint.to_string());
+
+        if let Some(limit) = too_large_limit(&e) {
+            return ProxyError::BodyTooLarge { limit };
+        }
+
+        if e.is_timeout() {
+            ProxyError::UpstreamTimeout { pool, endpoint }
+        } else if e.is_connect() {
+            ProxyError::UpstreamConnect {
+                pool,
+                endpoint,
+                error: e.to_string(),
+            }
+        } else if e.is_builder() {
+            ProxyError::Internal(e.to_string())
+        } else {
+            ProxyError::Upstream {
+                pool,
+                endpoint,
+                error: e.to_string(),
+            }
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::NoRoute => StatusCode::NOT_FOUND,
+            ProxyError::NoHealthyEndpoint { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ProxyError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ProxyError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ProxyError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ProxyError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::UpstreamConnect { .. } | ProxyError::Upstream { .. } => StatusCode::BAD_GATEWAY,
+            ProxyError::UpstreamTimeout { .. } | ProxyError::DeadlineExceeded { .. } => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ProxyError::NoRoute => "no_route",
+            ProxyError::NoHealthyEndpoint { .. } => "no_healthy_endpoint",
+            ProxyError::BodyTooLarge { .. } => "body_too_large",
+            ProxyError::BadRequest(_) => "bad_request",
+            ProxyError::Unauthorized(_) => "unauthorized",
+            ProxyError::RateLimited { .. } => "rate_limited",
+            ProxyError::Overloaded => "overloaded",
+            ProxyError::UpstreamConnect { .. } => "upstream_connect_failed",
+            ProxyError::UpstreamTimeout { .. } => "upstream_timeout",
+            ProxyError::DeadlineExceeded { .. } => "deadline_exceeded",
+            ProxyError::Upstream { .. } => "upstream_error",
+            ProxyError::Internal(_) => "proxy_error",
+        }
+    }
+
+    /// When the client should try again, for errors that will clear up.
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ProxyError::RateLimited { retry_after } => Some(*retry_after),
+            ProxyError::Overloaded => Some(OVERLOADED_RETRY_AFTER),
+            _ => None,
+        }
+    }
+
+    fn upstream(&self) -> (Option<&str>, Option<&str>) {
+        match self {
+            ProxyError::NoHealthyEndpoint { pool } => (Some(pool), None),
+            ProxyError::UpstreamConnect { pool, endpoint, .. }
+            | ProxyError::UpstreamTimeout { pool, endpoint }
+            | ProxyError::Upstream { pool, endpoint, .. } => (Some(pool), Some(endpoint)),
+            _ => (None, None),
+        }
+    }
+
+    /// A JSON body naming the error and, when one was picked, the upstream,
+    /// with the logid in the body and header.
+    pub fn into_response(self, logid: &str) -> Response {
+        let (upstream, endpoint) = self.upstream();
+
+        let body = ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+            upstream,
+            endpoint,
+            logid,
+        };
+
+        let mut response = (self.status(), Json(body)).into_response();
+
+        if let Ok(logid) = HeaderValue::from_str(logid) {
+            response.headers_mut().insert(LOGID_HEADER, logid);
+        }
+
+        if let ProxyError::Unauthorized(_) = self {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+
+        if let Some(retry_after) = self.retry_after() {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds(retry_after)));
+        }
+
+        response
+    }
+}
+
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::NoRoute => write!(f, "no route for request"),
+            ProxyError::NoHealthyEndpoint { pool } => write!(f, "no healthy endpoint: pool={pool}"),
+            ProxyError::BodyTooLarge { limit } => write!(f, "request body over limit: limit={limit}"),
+            ProxyError::BadRequest(e) => write!(f, "bad request: error={e}"),
+            ProxyError::Unauthorized(e) => write!(f, "unauthorized: error={e}"),
+            ProxyError::RateLimited { retry_after } => {
+                write!(f, "rate limited: retry_after_ms={}", retry_after.as_millis())
+            }
+            ProxyError::Overloaded => write!(f, "too many requests in flight"),
+            ProxyError::UpstreamConnect { pool, endpoint, error } => {
+                write!(f, "error connecting to upstream: pool={pool}, endpoint={endpoint}, error={error}")
+            }
+            ProxyError::UpstreamTimeout { pool, endpoint } => {
+                write!(f, "upstream timed out: pool={pool}, endpoint={endpoint}")
+            }
+            ProxyError::DeadlineExceeded { budget } => {
+                write!(f, "deadline exceeded: budget_ms={}", budget.as_millis())
+            }
+            ProxyError::Upstream { pool, endpoint, error } => {
+                write!(f, "upstream failed: pool={pool}, endpoint={endpoint}, error={error}")
+            }
+            ProxyError::Internal(e) => write!(f, "proxy error: error={e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
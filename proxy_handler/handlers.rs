@@ -1,221 +1,108 @@
-use core::panic;
-use std::{sync::Arc, time::Duration};
-
-use anyhow::Error;
-use axum::{routing::{get, post, patch, delete}, Router, response::{IntoResponse, Response}, http::{StatusCode, HeaderValue}, extract::{Request, State}, Json, body::{Body, to_bytes}};
-use hyper::header;
-use reqwest::Client;
-use shared::{init::init_tracing, header_helper::get_logid_blocking, layer::tracing_layer};
-use tokio::sync::OnceCell;
-use tracing::{info, error, warn};
-
-use super::util::{rewrite_uri, convert_status};
-
-pub async fn handle_get(State(state): State<Arc<Client>>, mut req: Request) -> Result<Response, StatusCode> {
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderValue, StatusCode},
+    response::Response,
+};
+use shared::{
+    client::{propagate_deadline, propagate_principal},
+    header_helper::{get_logid_blocking, LOGID_HEADER},
+    retry::{is_idempotent, should_retry},
+};
+use tracing::{info, error};
+
+use super::{
+    body::{buffer_request, check_length, stream_request, stream_response},
+    error::ProxyError,
+    headers::{from_reqwest, to_reqwest, upstream_headers},
+    pool::Lease,
+    state::ProxyState,
+    util::rewrite_uri,
+};
+
+/// Forwards any request to the upstream its route picks, passing end-to-end
+/// headers through both ways and streaming the bodies. The upstream's status
+/// is passed through unchanged; when there is no upstream response the
+/// proxy answers with a `ProxyError` body instead.
+///
+/// Requests that are safe to send twice are retried, which needs their body
+/// read up front; anything else is streamed and sent once.
+pub async fn forward(
+    State(state): State<ProxyState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    req: Request,
+) -> Response {
     let id = get_logid_blocking(req.headers());
 
-    info!("Rewriting uri");
-    rewrite_uri(&mut req).await;
-    
-    let req_uri = req.uri().to_string();
-    info!("Sending request: {req_uri}");
-
-    let res = state
-        .get(req_uri)
-        .header("logid", &id)
-        .send()
-        .await
-        .map_err(|e| {
-            error!("Error sending request: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    info!("Got response: {res:?}");
-
-    let headers = res.headers().clone();
-
-    let status = res.status();
-
-    let mut response = match res.text().await {
-        Ok(body) => Response::new(Body::from(body)),
-        Err(_) => Response::new(Body::from(""))
-    };
-
-    let content_type = match headers.get(reqwest::header::CONTENT_TYPE) {
-        Some(content_type) => content_type.as_bytes(),
-        None => b""
-    };
-
-    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_bytes(content_type).unwrap());
-    response.headers_mut().insert("logid", HeaderValue::from_str(&id).unwrap());
-
-    *response.status_mut() = convert_status(status);
-
-    info!("{response:?}");
-
-    Ok(response)
+    match send(&state, client, &id, req).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("resp: status={}, error={}", e.status(), e);
+            e.into_response(&id)
+        }
+    }
 }
 
-
-pub async fn handle_delete(State(state): State<Arc<Client>>, mut req: Request) -> Result<Response, StatusCode> {
-    let id = get_logid_blocking(req.headers());
+async fn send(state: &ProxyState, client: SocketAddr, id: &str, mut req: Request) -> Result<Response, ProxyError> {
+    let idempotent = is_idempotent(req.method(), req.headers());
 
     info!("Rewriting uri");
-    rewrite_uri(&mut req).await;
-    
+    let upstream = rewrite_uri(&mut req, id, &state.routes, &state.pools).await?;
+
     let req_uri = req.uri().to_string();
-    info!("Sending request: {req_uri}");
-
-    let res = state
-        .delete(req_uri)
-        .header("logid", &id)
-        .send()
-        .await
-        .map_err(|e| {
-            error!("Error sending request: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    info!("Sending request: method={}, uri={req_uri}", req.method());
 
-    info!("Got response: {res:?}");
+    check_length(req.headers(), upstream.max_body_bytes)?;
 
-    let headers = res.headers().clone();
+    let method = reqwest::Method::from_bytes(req.method().as_str().as_bytes())
+        .map_err(|e| ProxyError::BadRequest(e.to_string()))?;
 
-    let status = res.status();
+    let mut headers = upstream_headers(req.headers(), client);
+    headers.insert(LOGID_HEADER, HeaderValue::from_str(id).map_err(|e| ProxyError::BadRequest(e.to_string()))?);
+    let headers = to_reqwest(&headers);
 
-    let mut response = match res.text().await {
-        Ok(body) => Response::new(Body::from(body)),
-        Err(_) => Response::new(Body::from(""))
-    };
+    let request = || {
+        let request = state.client.request(method.clone(), &req_uri).headers(headers.clone());
 
-    let content_type = match headers.get(reqwest::header::CONTENT_TYPE) {
-        Some(content_type) => content_type.as_bytes(),
-        None => b""
+        propagate_principal(propagate_deadline(request))
     };
 
-    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_bytes(content_type).unwrap());
-    response.headers_mut().insert("logid", HeaderValue::from_str(&id).unwrap());
-
-    *response.status_mut() = convert_status(status);
-
-    info!("{response:?}");
-
-    Ok(response)
-}
-
-pub async fn handle_post(State(state): State<Arc<Client>>, mut req: Request) -> Result<Response, StatusCode> {
-    let id = get_logid_blocking(req.headers());
+    let res = match idempotent {
+        true => {
+            let body = buffer_request(req.into_body(), upstream.max_body_bytes).await?;
 
-    info!("Rewriting uri");
-
-    rewrite_uri(&mut req).await;
-    
-    let req_uri = req.uri().to_string();
-    info!("Sending request: {req_uri}");
-    
-    let req_headers = req.headers().clone();
-    let req_content_type = req_headers.get(header::CONTENT_TYPE).unwrap().as_bytes();
-    info!("Got content type: {req_content_type:?}");
-    let body = match to_bytes(req.into_body(), 1024).await {
-        Ok(body) => body,
-        Err(e) => {
-            error!("Error reading body: {}", e);
-            return Err(StatusCode::BAD_REQUEST);
+            state
+                .retry
+                .run(idempotent, || request().body(body.clone()).send(), should_retry)
+                .await
         }
-    };
-
-    let res = state
-        .post(req_uri)
-        .body(body)
-        .header("logid", &id)
-        .header(reqwest::header::CONTENT_TYPE, req_content_type)
-        .send()
-        .await
-        .map_err(|e| {
-            error!("Error sending request: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        false => request().body(stream_request(req.into_body(), upstream.max_body_bytes)).send().await,
+    }
+    .map_err(|e| ProxyError::from_send(e, upstream.lease.pool(), &upstream.lease.endpoint.to_string()))?;
 
     info!("Got response: {res:?}");
 
-    let headers = res.headers().clone();
-
-    let status = res.status();
-
-    let mut response = match res.text().await {
-        Ok(body) => Response::new(Body::from(body)),
-        Err(_) => Response::new(Body::from(""))
-    };
-
-    let content_type = match headers.get(reqwest::header::CONTENT_TYPE) {
-        Some(content_type) => content_type.as_bytes(),
-        None => b""
-    };
-
-    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_bytes(content_type).unwrap());
-    response.headers_mut().insert("logid", HeaderValue::from_str(&id).unwrap());
-
-    *response.status_mut() = convert_status(status);
-
-    info!("{response:?}");
-
-    Ok(response)
+    Ok(into_response(res, id, upstream.lease))
 }
 
-pub async fn handle_patch(State(state): State<Arc<Client>>, mut req: Request) -> Result<Response, StatusCode> {
-    let id = get_logid_blocking(req.headers());
-
-    info!("Rewriting uri");
+/// Copies the upstream status and end-to-end headers, streaming the body
+/// through untouched.
+fn into_response(res: reqwest::Response, id: &str, lease: Lease) -> Response {
+    // Any status reqwest accepted is one axum can send.
+    let status = StatusCode::from_u16(res.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
 
-    rewrite_uri(&mut req).await;
-    
-    let req_uri = req.uri().to_string();
-    info!("Sending request: {req_uri}");
-    
-    let req_headers = req.headers().clone();
-    let req_content_type = req_headers.get(header::CONTENT_TYPE).unwrap().as_bytes();
-    info!("Got content type: {req_content_type:?}");
-    let body = match to_bytes(req.into_body(), 1024).await {
-        Ok(body) => body,
-        Err(e) => {
-            error!("Error reading body: {}", e);
-            return Err(StatusCode::BAD_REQUEST);
-        }
-    };
+    let headers = from_reqwest(res.headers());
 
-    let res = state
-        .patch(req_uri)
-        .body(body)
-        .header("logid", &id)
-        .header(reqwest::header::CONTENT_TYPE, req_content_type)
-        .send()
-        .await
-        .map_err(|e| {
-            error!("Error sending request: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    let mut response = Response::new(stream_response(res, lease));
 
-    info!("Got response: {res:?}");
+    *response.headers_mut() = headers;
 
-    let headers = res.headers().clone();
+    response.headers_mut().insert(LOGID_HEADER, HeaderValue::from_str(id).unwrap());
 
-    let status = res.status();
-
-    let mut response = match res.text().await {
-        Ok(body) => Response::new(Body::from(body)),
-        Err(_) => Response::new(Body::from(""))
-    };
-
-    let content_type = match headers.get(reqwest::header::CONTENT_TYPE) {
-        Some(content_type) => content_type.as_bytes(),
-        None => b""
-    };
-
-    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_bytes(content_type).unwrap());
-    response.headers_mut().insert("logid", HeaderValue::from_str(&id).unwrap());
-
-    *response.status_mut() = convert_status(status);
+    *response.status_mut() = status;
 
     info!("{response:?}");
 
-    Ok(response)
-}
\ No newline at end of file
+    response
+}
@@ -0,0 +1,197 @@
+use std::net::SocketAddr;
+
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use shared::header_helper::{DEADLINE_HEADER, PRINCIPAL_HEADER};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// We only listen on plain HTTP.
+const PROTO: &str = "http";
+
+/// Connection-specific headers that apply to one hop only, RFC 9110 section
+/// 7.6.1, plus the non-standard ones still seen in the wild.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers the services trust to come from the proxy, which sets them itself
+/// from the route's deadline and the authenticated principal.
+const INTERNAL: [&str; 2] = [DEADLINE_HEADER, PRINCIPAL_HEADER];
+
+/// Drops hop-by-hop headers, including any the `Connection` header names.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in named {
+        headers.remove(name);
+    }
+
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+/// The headers to send upstream: the client's end-to-end headers, less
+/// `Host`, which becomes the upstream's, and any internal ones it sent, plus
+/// `X-Forwarded-For/Proto/Host` and `Forwarded` describing this hop.
+pub fn upstream_headers(incoming: &HeaderMap, client: SocketAddr) -> HeaderMap {
+    let mut headers = incoming.clone();
+
+    strip_hop_by_hop(&mut headers);
+
+    for name in INTERNAL {
+        headers.remove(name);
+    }
+
+    let host = headers.remove(header::HOST);
+    let ip = client.ip().to_string();
+
+    let forwarded_for = match headers.get(X_FORWARDED_FOR).and_then(|value| value.to_str().ok()) {
+        Some(previous) => format!("{previous}, {ip}"),
+        None => ip.clone(),
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(PROTO));
+    }
+
+    if let Some(host) = &host {
+        if !headers.contains_key(X_FORWARDED_HOST) {
+            headers.insert(X_FORWARDED_HOST, host.clone());
+        }
+    }
+
+    // IPv6 addresses have to be quoted, RFC 7239 section 6.
+    let node = match client {
+        SocketAddr::V4(_) => ip,
+        SocketAddr::V6(_) => format!("\"[{ip}]\""),
+    };
+
+    let mut element = format!("for={node};proto={PROTO}");
+
+    if let Some(host) = host.as_ref().and_then(|host| host.to_str().ok()) {
+        element.push_str(&format!(";host=\"{host}\""));
+    }
+
+    let forwarded = match headers.get(header::FORWARDED).and_then(|value| value.to_str().ok()) {
+        Some(previous) => format!("{previous}, {element}"),
+        None => element,
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&forwarded) {
+        headers.insert(header::FORWARDED, value);
+    }
+
+    headers
+}
+
+/// axum and reqwest use different versions of `http`, so headers are copied
+/// across by name and value.
+pub fn to_reqwest(headers: &HeaderMap) -> reqwest::header::HeaderMap {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((
+                reqwest::header::HeaderName::from_bytes(name.as_str().as_bytes()).ok()?,
+                reqwest::header::HeaderValue::from_bytes(value.as_bytes()).ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// The upstream response's end-to-end headers.
+pub fn from_reqwest(headers: &reqwest::header::HeaderMap) -> HeaderMap {
+    let mut headers: HeaderMap = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::from_bytes(name.as_str().as_bytes()).ok()?,
+                HeaderValue::from_bytes(value.as_bytes()).ok()?,
+            ))
+        })
+        .collect();
+
+    strip_hop_by_hop(&mut headers);
+
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> SocketAddr {
+        "10.0.0.9:4000".parse().unwrap()
+    }
+
+    #[test]
+    fn strips_hop_by_hop_and_connection_named_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive, x-session"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-session", HeaderValue::from_static("abc"));
+        headers.insert(header::TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+
+        strip_hop_by_hop(&mut headers);
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[header::ACCEPT], "application/json");
+    }
+
+    #[test]
+    fn drops_internal_headers_the_client_sent() {
+        let mut headers = HeaderMap::new();
+        headers.insert(DEADLINE_HEADER, HeaderValue::from_static("600000"));
+        headers.insert(PRINCIPAL_HEADER, HeaderValue::from_static("admin"));
+        headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+
+        let upstream = upstream_headers(&headers, client());
+
+        assert!(upstream.get(DEADLINE_HEADER).is_none());
+        assert!(upstream.get(PRINCIPAL_HEADER).is_none());
+        assert_eq!(upstream[header::ACCEPT], "application/json");
+    }
+
+    #[test]
+    fn describes_this_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("api.example"));
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("192.0.2.1"));
+
+        let upstream = upstream_headers(&headers, client());
+
+        assert!(upstream.get(header::HOST).is_none());
+        assert_eq!(upstream[X_FORWARDED_FOR], "192.0.2.1, 10.0.0.9");
+        assert_eq!(upstream[X_FORWARDED_PROTO], "http");
+        assert_eq!(upstream[X_FORWARDED_HOST], "api.example");
+        assert_eq!(upstream[header::FORWARDED], "for=10.0.0.9;proto=http;host=\"api.example\"");
+    }
+
+    #[test]
+    fn quotes_ipv6_clients_in_forwarded() {
+        let upstream = upstream_headers(&HeaderMap::new(), "[2001:db8::1]:4000".parse().unwrap());
+
+        assert_eq!(upstream[header::FORWARDED], "for=\"[2001:db8::1]\";proto=http");
+    }
+}
@@ -1,21 +1,28 @@
-use core::panic;
-use std::{sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::Error;
-use axum::{routing::{get, post, patch, delete}, Router, response::{IntoResponse, Response}, http::{StatusCode, HeaderValue}, extract::{Request, State}, Json, body::{Body, to_bytes}};
-use hyper::header;
+use axum::{middleware, routing::any, Router};
 use reqwest::Client;
-use shared::{init::init_tracing, header_helper::get_logid_blocking, layer::{tracing_layer, logid_layer}};
-use tokio::sync::OnceCell;
-use tracing::{info, error, warn};
-use handlers::{
-    handle_get,
-    handle_post,
-    handle_patch,
-    handle_delete
-};
+use shared::{init::init_tracing, layer::{tracing_layer, logid_layer}, registry::ServiceRegistry, retry::RetryPolicy};
+use tracing::info;
+use auth::{authenticate, Authenticator};
+use handlers::forward;
+use deadline::route_deadline;
+use pool::Pools;
+use ratelimit::{rate_limit, shed_load, RateLimiter};
+use routes::RouteTable;
+use state::ProxyState;
 
+pub mod auth;
+pub mod body;
+pub mod deadline;
+pub mod error;
 pub mod handlers;
+pub mod headers;
+pub mod pool;
+pub mod ratelimit;
+pub mod routes;
+pub mod state;
 pub mod util;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 1)]
@@ -26,19 +33,41 @@ async fn main() -> Result<(), Error> {
 
     info!("Creating client pool");
 
-    let app_state = Arc::new(
+    let client = Arc::new(
         Client::builder()
         .connect_timeout(Duration::from_millis(1000))
         .build()?
     );
 
+    let registry = Arc::new(ServiceRegistry::from_env());
+    registry.spawn_refresher();
+
+    let routes = RouteTable::from_env()?;
+
+    let pools = Arc::new(Pools::new(&routes, registry));
+    pools.spawn_health_checks(client.clone());
+
+    let limiter = Arc::new(RateLimiter::new(&routes)?);
+    let auth = Arc::new(Authenticator::new(routes.auth())?);
+
+    let app_state = ProxyState {
+        client,
+        retry: Arc::new(RetryPolicy::from_env()),
+        routes: Arc::new(routes),
+        pools,
+        limiter,
+        auth,
+    };
+
     info!("Creating routers");
 
     let router = Router::new()
-        .route("/*path", get(handle_get))
-        .route("/*path", post(handle_post))
-        .route("/*path", patch(handle_patch))
-        .route("/*path", delete(handle_delete))
+        .route("/", any(forward))
+        .route("/*path", any(forward))
+        .layer(middleware::from_fn_with_state(app_state.clone(), route_deadline))
+        .layer(middleware::from_fn_with_state(app_state.clone(), rate_limit))
+        .layer(middleware::from_fn_with_state(app_state.clone(), authenticate))
+        .layer(middleware::from_fn_with_state(app_state.clone(), shed_load))
         .layer(tracing_layer())
         .layer(logid_layer())
         .with_state(app_state.clone());
@@ -53,7 +82,7 @@ async fn main() -> Result<(), Error> {
     Ok(
         axum::serve(
             listener, 
-            router
+            router.into_make_service_with_connect_info::<SocketAddr>()
         ).await?
     )
 }
\ No newline at end of file
@@ -0,0 +1,231 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use reqwest::Client;
+use serde::Deserialize;
+use shared::registry::{Endpoint, ServiceRegistry};
+use tracing::{info, warn};
+
+use crate::routes::RouteTable;
+
+const DEFAULT_HEALTH_PATH: &str = "/health";
+const DEFAULT_HEALTH_INTERVAL_MS: u64 = 2000;
+const DEFAULT_HEALTH_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_UNHEALTHY_AFTER: u32 = 2;
+
+/// How a pool picks one of its healthy endpoints for each request.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    /// The endpoint with the fewest requests in flight from this proxy.
+    LeastOutstanding,
+    /// The same endpoint for the same logid while the pool doesn't change.
+    HashLogid,
+    /// The same endpoint for the same path while the pool doesn't change.
+    HashPath,
+}
+
+/// A pool's `[pools.<name>]` table in the routes file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PoolConfig {
+    pub strategy: Strategy,
+    pub health_path: String,
+    pub health_interval_ms: u64,
+    pub health_timeout_ms: u64,
+    /// Consecutive failed checks before an endpoint is taken out; one passing
+    /// check puts it back.
+    pub unhealthy_after: u32,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::default(),
+            health_path: DEFAULT_HEALTH_PATH.to_string(),
+            health_interval_ms: DEFAULT_HEALTH_INTERVAL_MS,
+            health_timeout_ms: DEFAULT_HEALTH_TIMEOUT_MS,
+            unhealthy_after: DEFAULT_UNHEALTHY_AFTER,
+        }
+    }
+}
+
+/// The endpoints registered for one service name, balanced between by its
+/// strategy.
+pub struct Pool {
+    name: String,
+    config: PoolConfig,
+    registry: Arc<ServiceRegistry>,
+    next: AtomicUsize,
+    outstanding: Mutex<HashMap<Endpoint, usize>>,
+    failures: Mutex<HashMap<Endpoint, u32>>,
+}
+
+/// An endpoint picked for one request, counted as outstanding until dropped.
+pub struct Lease {
+    pub endpoint: Endpoint,
+    pool: Arc<Pool>,
+}
+
+impl Lease {
+    pub fn pool(&self) -> &str {
+        &self.pool.name
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut outstanding = self.pool.outstanding.lock().unwrap();
+
+        if let Some(count) = outstanding.get_mut(&self.endpoint) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+impl Pool {
+    pub fn new(name: &str, config: PoolConfig, registry: Arc<ServiceRegistry>) -> Self {
+        Self {
+            name: name.to_string(),
+            config,
+            registry,
+            next: AtomicUsize::new(0),
+            outstanding: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Picks a healthy endpoint for a request, `None` if there isn't one.
+    pub async fn pick(self: &Arc<Self>, logid: &str, path: &str) -> Option<Lease> {
+        let endpoints = self.registry.endpoints(&self.name).await;
+
+        if endpoints.is_empty() {
+            return None;
+        }
+
+        let mut outstanding = self.outstanding.lock().unwrap();
+
+        let endpoint = match self.config.strategy {
+            Strategy::RoundRobin => endpoints[self.next.fetch_add(1, Ordering::Relaxed) % endpoints.len()].clone(),
+            Strategy::LeastOutstanding => {
+                // Start from a rotating offset so ties don't all go to the
+                // first endpoint.
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+
+                (0..endpoints.len())
+                    .map(|i| &endpoints[(offset + i) % endpoints.len()])
+                    .min_by_key(|endpoint| outstanding.get(*endpoint).copied().unwrap_or_default())
+                    .cloned()?
+            }
+            Strategy::HashLogid => rendezvous(&endpoints, logid)?,
+            Strategy::HashPath => rendezvous(&endpoints, path)?,
+        };
+
+        *outstanding.entry(endpoint.clone()).or_default() += 1;
+
+        Some(Lease {
+            endpoint,
+            pool: self.clone(),
+        })
+    }
+
+    /// Checks every endpoint's health route each `health_interval_ms`,
+    /// marking it in the registry.
+    pub fn spawn_health_checks(self: &Arc<Self>, client: Arc<Client>) {
+        let pool = self.clone();
+        let interval = Duration::from_millis(self.config.health_interval_ms);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let endpoints = pool.registry.all_endpoints(&pool.name).await;
+
+                join_all(endpoints.iter().map(|endpoint| pool.check(&client, endpoint))).await;
+            }
+        });
+    }
+
+    async fn check(&self, client: &Client, endpoint: &Endpoint) {
+        let url = format!("{}{}", endpoint.url(), self.config.health_path);
+
+        let healthy = client
+            .get(&url)
+            .timeout(Duration::from_millis(self.config.health_timeout_ms))
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success());
+
+        let mut failures = self.failures.lock().unwrap();
+
+        let count = failures.entry(endpoint.clone()).or_default();
+
+        match healthy {
+            true => *count = 0,
+            false => {
+                *count += 1;
+                warn!("health check failed: pool={}, endpoint={endpoint}, failures={count}", self.name);
+            }
+        }
+
+        match *count {
+            0 => self.registry.set_healthy(endpoint, true),
+            count if count >= self.config.unhealthy_after => self.registry.set_healthy(endpoint, false),
+            _ => {}
+        }
+    }
+}
+
+/// Highest random weight hashing: only keys on an endpoint that leaves move.
+fn rendezvous(endpoints: &[Endpoint], key: &str) -> Option<Endpoint> {
+    endpoints
+        .iter()
+        .max_by_key(|endpoint| {
+            let mut hasher = DefaultHasher::new();
+            (key, *endpoint).hash(&mut hasher);
+            hasher.finish()
+        })
+        .cloned()
+}
+
+/// Every pool named in the route table.
+pub struct Pools {
+    pools: HashMap<String, Arc<Pool>>,
+}
+
+impl Pools {
+    pub fn new(routes: &RouteTable, registry: Arc<ServiceRegistry>) -> Self {
+        let pools = routes
+            .pool_names()
+            .map(|name| {
+                let config = routes.pool_config(name);
+
+                info!("upstream pool: pool={name}, config={config:?}");
+
+                (name.to_string(), Arc::new(Pool::new(name, config, registry.clone())))
+            })
+            .collect();
+
+        Self { pools }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Pool>> {
+        self.pools.get(name)
+    }
+
+    pub fn spawn_health_checks(&self, client: Arc<Client>) {
+        for pool in self.pools.values() {
+            pool.spawn_health_checks(client.clone());
+        }
+    }
+}
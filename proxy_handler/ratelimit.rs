@@ -0,0 +1,331 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use lru::LruCache;
+use serde::Deserialize;
+use shared::{context::current_principal, header_helper::get_logid_blocking};
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use crate::{
+    body::hold,
+    error::ProxyError,
+    routes::{Route, RouteTable},
+};
+
+const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATELIMIT_RESET: &str = "ratelimit-reset";
+
+const DEFAULT_MAX_KEYS: usize = 10000;
+
+/// What a rate limit counts requests by. Requests without a principal are
+/// counted by client IP instead.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// The address connecting to the proxy.
+    #[default]
+    ClientIp,
+    /// The principal `authenticate` found the request's credentials to
+    /// belong to, so made-up keys can't buy a fresh bucket.
+    Principal,
+}
+
+/// A `[rate_limits.<name>]` table in the routes file: a token bucket per key
+/// holding up to `burst` requests and refilling at `rate` a second.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub key: RateLimitKey,
+    pub rate: f64,
+    pub burst: u32,
+    /// Keys tracked at once; the least recently seen are forgotten, which
+    /// refills their bucket.
+    #[serde(default = "default_max_keys")]
+    pub max_keys: usize,
+}
+
+fn default_max_keys() -> usize {
+    DEFAULT_MAX_KEYS
+}
+
+/// Where a key's bucket stands after a request.
+#[derive(Debug)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the bucket is full again.
+    pub reset: Duration,
+    /// Set when the request was refused: until the next token.
+    pub retry_after: Option<Duration>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Limit {
+    config: RateLimitConfig,
+    buckets: Mutex<LruCache<String, Bucket>>,
+}
+
+impl Limit {
+    fn new(config: RateLimitConfig) -> Result<Self, Error> {
+        if config.rate <= 0.0 || config.burst == 0 {
+            return Err(anyhow!("rate limit needs a positive rate and burst: config={config:?}"));
+        }
+
+        let max_keys = NonZeroUsize::new(config.max_keys).ok_or_else(|| anyhow!("rate limit needs max_keys: config={config:?}"))?;
+
+        Ok(Self {
+            config,
+            buckets: Mutex::new(LruCache::new(max_keys)),
+        })
+    }
+
+    /// `principal` is the one `authenticate` set, which runs first.
+    fn key(&self, principal: Option<String>, client: SocketAddr) -> String {
+        let principal = match self.config.key {
+            RateLimitKey::ClientIp => None,
+            RateLimitKey::Principal => principal,
+        };
+
+        match principal {
+            Some(principal) => format!("principal:{principal}"),
+            None => format!("ip:{}", client.ip()),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket if it has one.
+    fn take(&self, key: &str, now: Instant) -> Quota {
+        let burst = self.config.burst as f64;
+        let rate = self.config.rate;
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let bucket = buckets.get_or_insert_mut(key.to_string(), || Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+
+        let retry_after = match bucket.tokens >= 1.0 {
+            true => {
+                bucket.tokens -= 1.0;
+                None
+            }
+            false => Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate)),
+        };
+
+        Quota {
+            limit: self.config.burst,
+            remaining: bucket.tokens.floor() as u32,
+            reset: Duration::from_secs_f64((burst - bucket.tokens) / rate),
+            retry_after,
+        }
+    }
+}
+
+/// The rate limits named in the route table, and the cap on requests in
+/// flight through the proxy.
+pub struct RateLimiter {
+    limits: HashMap<String, Limit>,
+    in_flight: Option<Arc<Semaphore>>,
+}
+
+impl RateLimiter {
+    pub fn new(routes: &RouteTable) -> Result<Self, Error> {
+        let limits = routes
+            .rate_limits()
+            .map(|(name, config)| {
+                info!("rate limit: name={name}, config={config:?}");
+
+                Ok((name.to_string(), Limit::new(config.clone())?))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let max_concurrency = routes.max_concurrency();
+
+        info!("concurrency limit: max_concurrency={max_concurrency}");
+
+        Ok(Self {
+            limits,
+            in_flight: (max_concurrency > 0).then(|| Arc::new(Semaphore::new(max_concurrency))),
+        })
+    }
+
+    /// Counts a request that failed authentication against the client IP's
+    /// bucket on its route, as `authenticate` answers it before `rate_limit`
+    /// sees it; otherwise credentials could be guessed without limit. Once
+    /// the bucket is empty, returns how long until the next attempt.
+    pub fn charge_unauthenticated(&self, routes: &RouteTable, route: &Route, client: SocketAddr) -> Option<Duration> {
+        let limit = self.limits.get(routes.rate_limit(route)?)?;
+        let key = limit.key(None, client);
+
+        let retry_after = limit.take(&bucket(route, &key), Instant::now()).retry_after;
+
+        if let Some(retry_after) = retry_after {
+            warn!("rate limited unauthenticated: route={}, key={key}, retry_after_ms={}", route.prefix, retry_after.as_millis());
+        }
+
+        retry_after
+    }
+}
+
+/// Each route has its own buckets.
+fn bucket(route: &Route, key: &str) -> String {
+    format!("{} {key}", route.prefix)
+}
+
+/// Counts each request against its route's rate limit, answering 429 once
+/// the key's bucket is empty. Responses on limited routes carry the
+/// `RateLimit-Limit/Remaining/Reset` headers.
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    State(routes): State<Arc<RouteTable>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let host = request.headers().get(header::HOST).and_then(|host| host.to_str().ok());
+
+    let limited = routes
+        .find(request.method(), host, request.uri().path())
+        .and_then(|route| Some((route, limiter.limits.get(routes.rate_limit(route)?)?)));
+
+    let (route, limit) = match limited {
+        Some(limited) => limited,
+        None => return next.run(request).await,
+    };
+
+    let key = limit.key(current_principal(), client);
+    let quota = limit.take(&bucket(route, &key), Instant::now());
+
+    let mut response = match quota.retry_after {
+        Some(retry_after) => {
+            warn!("rate limited: route={}, key={key}, retry_after_ms={}", route.prefix, retry_after.as_millis());
+
+            ProxyError::RateLimited { retry_after }.into_response(&get_logid_blocking(request.headers()))
+        }
+        None => next.run(request).await,
+    };
+
+    let headers = response.headers_mut();
+
+    for (name, value) in [
+        (RATELIMIT_LIMIT, quota.limit as u64),
+        (RATELIMIT_REMAINING, quota.remaining as u64),
+        (RATELIMIT_RESET, seconds(quota.reset)),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+
+    response
+}
+
+/// Answers 503 straight away when `max_concurrency` requests are already in
+/// flight, rather than queueing behind them. A request counts until its
+/// response body is done.
+pub async fn shed_load(State(limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    let in_flight = match &limiter.in_flight {
+        Some(in_flight) => in_flight,
+        None => return next.run(request).await,
+    };
+
+    let permit = match in_flight.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            warn!("shedding load: path={}", request.uri().path());
+            return ProxyError::Overloaded.into_response(&get_logid_blocking(request.headers()));
+        }
+    };
+
+    next.run(request).await.map(|body| hold(body, permit))
+}
+
+/// Whole seconds, rounded up so clients don't come back early.
+pub fn seconds(duration: Duration) -> u64 {
+    duration.as_millis().div_ceil(1000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(key: RateLimitKey, rate: f64, burst: u32) -> Limit {
+        Limit::new(RateLimitConfig {
+            key,
+            rate,
+            burst,
+            max_keys: 2,
+        })
+        .unwrap()
+    }
+
+    fn client(ip: &str) -> SocketAddr {
+        format!("{ip}:4000").parse().unwrap()
+    }
+
+    #[test]
+    fn refills_at_rate_up_to_burst() {
+        let limit = limit(RateLimitKey::ClientIp, 2.0, 2);
+        let start = Instant::now();
+
+        assert!(limit.take("a", start).retry_after.is_none());
+        assert!(limit.take("a", start).retry_after.is_none());
+
+        let refused = limit.take("a", start);
+        assert_eq!(refused.remaining, 0);
+        assert_eq!(refused.retry_after, Some(Duration::from_millis(500)));
+
+        // Half a second buys one token back; an hour never more than `burst`.
+        assert!(limit.take("a", start + Duration::from_millis(500)).retry_after.is_none());
+
+        let later = limit.take("a", start + Duration::from_secs(3600));
+        assert!(later.retry_after.is_none());
+        assert_eq!(later.remaining, 1);
+    }
+
+    #[test]
+    fn keys_have_their_own_buckets() {
+        let limit = limit(RateLimitKey::ClientIp, 1.0, 1);
+        let now = Instant::now();
+
+        assert!(limit.take("a", now).retry_after.is_none());
+        assert!(limit.take("b", now).retry_after.is_none());
+        assert!(limit.take("a", now).retry_after.is_some());
+    }
+
+    #[test]
+    fn keys_on_principal_falling_back_to_client_ip() {
+        let limit = limit(RateLimitKey::Principal, 1.0, 1);
+
+        assert_eq!(limit.key(None, client("10.0.0.1")), "ip:10.0.0.1");
+        assert_eq!(limit.key(Some("ops".to_string()), client("10.0.0.1")), "principal:ops");
+        assert_eq!(limit.key(Some("ops".to_string()), client("10.0.0.2")), "principal:ops");
+    }
+
+    #[test]
+    fn client_ip_ignores_principal() {
+        let limit = limit(RateLimitKey::ClientIp, 1.0, 1);
+
+        assert_eq!(limit.key(Some("ops".to_string()), client("10.0.0.1")), "ip:10.0.0.1");
+    }
+}
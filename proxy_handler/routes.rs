@@ -0,0 +1,359 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use axum::http::{uri::Authority, Method};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{auth::AuthConfig, pool::PoolConfig, ratelimit::RateLimitConfig};
+
+/// Pool used by the catch-all route when no route file is configured, so
+/// `SERVICE_ADDRESS`/`SERVICE_PORT` keep working on their own.
+const DEFAULT_POOL: &str = "service";
+
+const DEFAULT_MAX_BODY_BYTES: u64 = 1024 * 1024;
+const DEFAULT_MAX_CONCURRENCY: usize = 512;
+const DEFAULT_DEADLINE_MS: u64 = 5000;
+
+/// Sends requests under `prefix` to the upstream pool `pool`, looked up in
+/// the service registry.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Route {
+    pub prefix: String,
+    pub pool: String,
+    /// Methods the route accepts; any when empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// `Host` the route accepts, ignoring any port; any when unset. IPv6
+    /// addresses may be given with or without brackets.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Drop `prefix` from the path before forwarding.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Replace `prefix` with this before forwarding. Takes precedence over
+    /// `strip_prefix`.
+    #[serde(default)]
+    pub rewrite: Option<String>,
+    /// Largest request body the route accepts, overriding the table's.
+    #[serde(default)]
+    pub max_body_bytes: Option<u64>,
+    /// End-to-end deadline for the route's requests, overriding the table's.
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
+    /// Name of the `[rate_limits.<name>]` table limiting the route,
+    /// overriding the table's.
+    #[serde(default)]
+    pub rate_limit: Option<String>,
+    /// Let requests through without credentials when `[auth]` is set.
+    #[serde(default)]
+    pub public: bool,
+}
+
+impl Route {
+    fn matches(&self, method: &Method, host: Option<&str>, path: &str) -> bool {
+        let method_matches = self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method.as_str()));
+
+        let host_matches = self.host.as_deref().is_none_or(|expected| {
+            host.and_then(|host| host.parse::<Authority>().ok())
+                .is_some_and(|host| unbracket(host.host()).eq_ignore_ascii_case(unbracket(expected)))
+        });
+
+        self.matches_path(path) && method_matches && host_matches
+    }
+
+    /// Whether `path` is under `prefix` segment by segment, so `/entity`
+    /// takes `/entity/a` and `/entity:batchGet` but not `/entityx`.
+    fn matches_path(&self, path: &str) -> bool {
+        match path.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with(['/', ':']),
+            None => false,
+        }
+    }
+
+    /// The path and query to send upstream for `path_and_query`.
+    pub fn upstream_path(&self, path_and_query: &str) -> String {
+        let rest = match path_and_query.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => rest,
+            None => return path_and_query.to_string(),
+        };
+
+        let rewritten = match (&self.rewrite, self.strip_prefix) {
+            (Some(rewrite), _) => format!("{}{}", rewrite.trim_end_matches('/'), rest),
+            (None, true) => rest.to_string(),
+            (None, false) => return path_and_query.to_string(),
+        };
+
+        match rewritten.starts_with('/') {
+            true => rewritten,
+            false => format!("/{rewritten}"),
+        }
+    }
+}
+
+/// `[::1]` as `::1`, so IPv6 hosts compare the same either way.
+fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteTable {
+    routes: Vec<Route>,
+    /// Balancing and health checks for each pool, by name.
+    #[serde(default)]
+    pools: HashMap<String, PoolConfig>,
+    /// Largest request body for routes without their own limit.
+    #[serde(default = "default_max_body_bytes")]
+    max_body_bytes: u64,
+    /// Token buckets routes can be limited by, by name.
+    #[serde(default)]
+    rate_limits: HashMap<String, RateLimitConfig>,
+    /// Rate limit for routes without their own; none when unset.
+    #[serde(default)]
+    rate_limit: Option<String>,
+    /// Deadline for routes without their own.
+    #[serde(default = "default_deadline_ms")]
+    deadline_ms: u64,
+    /// Requests in flight through the proxy before more are turned away; no
+    /// limit when zero.
+    #[serde(default = "default_max_concurrency")]
+    max_concurrency: usize,
+    /// How requests authenticate; they don't need to when unset.
+    #[serde(default)]
+    auth: Option<AuthConfig>,
+}
+
+/// `PROXY_MAX_BODY_BYTES`, or 1 MiB.
+fn default_max_body_bytes() -> u64 {
+    std::env::var("PROXY_MAX_BODY_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES)
+}
+
+/// `DEADLINE_DEFAULT_MS`, or 5 seconds.
+fn default_deadline_ms() -> u64 {
+    std::env::var("DEADLINE_DEFAULT_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .unwrap_or(DEFAULT_DEADLINE_MS)
+}
+
+/// `PROXY_MAX_CONCURRENCY`, or 512.
+fn default_max_concurrency() -> usize {
+    std::env::var("PROXY_MAX_CONCURRENCY")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CONCURRENCY)
+}
+
+impl RouteTable {
+    /// Reads the TOML file at `PROXY_ROUTES_FILE`, a list of `[[routes]]` and
+    /// optional `[pools.<name>]`, `[rate_limits.<name>]` and `[auth]` tables.
+    /// Without one every request goes to the `service` pool.
+    pub fn from_env() -> Result<Self, Error> {
+        let table = match std::env::var("PROXY_ROUTES_FILE") {
+            Ok(path) => {
+                info!("reading routes: path={path}");
+                let mut table: Self = toml::from_str(&std::fs::read_to_string(&path)?)?;
+
+                if let (Some(auth), Some(dir)) = (&mut table.auth, Path::new(&path).parent()) {
+                    auth.resolve(dir);
+                }
+
+                table
+            }
+            Err(_) => Self {
+                routes: vec![Route {
+                    prefix: "/".to_string(),
+                    pool: DEFAULT_POOL.to_string(),
+                    methods: Vec::new(),
+                    host: None,
+                    strip_prefix: false,
+                    rewrite: None,
+                    max_body_bytes: None,
+                    deadline_ms: None,
+                    rate_limit: None,
+                    public: false,
+                }],
+                pools: HashMap::new(),
+                max_body_bytes: default_max_body_bytes(),
+                deadline_ms: default_deadline_ms(),
+                rate_limits: HashMap::new(),
+                rate_limit: None,
+                max_concurrency: default_max_concurrency(),
+                auth: None,
+            },
+        };
+
+        let unknown = table
+            .routes
+            .iter()
+            .filter_map(|route| table.rate_limit(route))
+            .find(|name| !table.rate_limits.contains_key(*name));
+
+        if let Some(name) = unknown {
+            return Err(anyhow!("unknown rate limit: name={name}"));
+        }
+
+        info!("routes: routes={:?}, max_body_bytes={}", table.routes, table.max_body_bytes);
+
+        Ok(table)
+    }
+
+    /// The route with the longest prefix matching the request; the first
+    /// listed wins a tie.
+    pub fn find(&self, method: &Method, host: Option<&str>, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .rev()
+            .filter(|route| route.matches(method, host, path))
+            .max_by_key(|route| route.prefix.len())
+    }
+
+    /// Every pool some route sends requests to.
+    pub fn pool_names(&self) -> impl Iterator<Item = &str> {
+        self.routes
+            .iter()
+            .map(|route| route.pool.as_str())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+    }
+
+    pub fn max_body_bytes(&self, route: &Route) -> u64 {
+        route.max_body_bytes.unwrap_or(self.max_body_bytes)
+    }
+
+    /// Deadline for requests on `route`, or on no route at all.
+    pub fn deadline(&self, route: Option<&Route>) -> Duration {
+        Duration::from_millis(route.and_then(|route| route.deadline_ms).unwrap_or(self.deadline_ms))
+    }
+
+    /// Name of the rate limit for `route`, if it has one.
+    pub fn rate_limit<'a>(&'a self, route: &'a Route) -> Option<&'a str> {
+        route.rate_limit.as_deref().or(self.rate_limit.as_deref())
+    }
+
+    pub fn rate_limits(&self) -> impl Iterator<Item = (&str, &RateLimitConfig)> {
+        self.rate_limits.iter().map(|(name, config)| (name.as_str(), config))
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    pub fn auth(&self) -> Option<&AuthConfig> {
+        self.auth.as_ref()
+    }
+
+    pub fn pool_config(&self, name: &str) -> PoolConfig {
+        self.pools.get(name).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> RouteTable {
+        toml::from_str(
+            r#"
+            [[routes]]
+            prefix = "/entity"
+            pool = "entity"
+
+            [[routes]]
+            prefix = "/services/entity/health"
+            pool = "entity"
+            methods = ["GET"]
+            rewrite = "/health"
+
+            [[routes]]
+            prefix = "/strip"
+            pool = "entity"
+            strip_prefix = true
+
+            [[routes]]
+            prefix = "/"
+            pool = "admin"
+            host = "admin.example"
+
+            [[routes]]
+            prefix = "/"
+            pool = "combo"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn pool<'a>(table: &'a RouteTable, method: Method, host: Option<&str>, path: &str) -> Option<&'a str> {
+        table.find(&method, host, path).map(|route| route.pool.as_str())
+    }
+
+    #[test]
+    fn matches_whole_segments() {
+        let table = table();
+
+        assert_eq!(pool(&table, Method::GET, None, "/entity"), Some("entity"));
+        assert_eq!(pool(&table, Method::GET, None, "/entity/a"), Some("entity"));
+        assert_eq!(pool(&table, Method::POST, None, "/entity:batchGet"), Some("entity"));
+        assert_eq!(pool(&table, Method::GET, None, "/entityx"), Some("combo"));
+    }
+
+    #[test]
+    fn narrows_by_method_and_host() {
+        let table = table();
+
+        assert_eq!(pool(&table, Method::GET, None, "/services/entity/health"), Some("entity"));
+        assert_eq!(pool(&table, Method::POST, None, "/services/entity/health"), Some("combo"));
+        assert_eq!(pool(&table, Method::GET, None, "/services/entity/a"), Some("combo"));
+        assert_eq!(pool(&table, Method::GET, Some("admin.example:8080"), "/x"), Some("admin"));
+        assert_eq!(pool(&table, Method::GET, Some("ADMIN.example"), "/x"), Some("admin"));
+    }
+
+    #[test]
+    fn matches_ipv6_hosts() {
+        let table: RouteTable = toml::from_str(
+            r#"
+            [[routes]]
+            prefix = "/"
+            pool = "local"
+            host = "::1"
+
+            [[routes]]
+            prefix = "/"
+            pool = "internal"
+            host = "[fd00::2]"
+
+            [[routes]]
+            prefix = "/"
+            pool = "combo"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(pool(&table, Method::GET, Some("[::1]:8080"), "/x"), Some("local"));
+        assert_eq!(pool(&table, Method::GET, Some("[::1]"), "/x"), Some("local"));
+        assert_eq!(pool(&table, Method::GET, Some("[fd00::2]:80"), "/x"), Some("internal"));
+        assert_eq!(pool(&table, Method::GET, Some("[::2]:8080"), "/x"), Some("combo"));
+    }
+
+    #[test]
+    fn rewrites_and_strips_prefixes() {
+        let table = table();
+        let route = |path| table.find(&Method::GET, None, path).unwrap();
+
+        assert_eq!(route("/services/entity/health").upstream_path("/services/entity/health?x=1"), "/health?x=1");
+        assert_eq!(route("/strip/a").upstream_path("/strip/a?x=1"), "/a?x=1");
+        assert_eq!(route("/strip").upstream_path("/strip"), "/");
+        assert_eq!(route("/entity/a").upstream_path("/entity/a"), "/entity/a");
+    }
+}
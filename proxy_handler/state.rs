@@ -0,0 +1,17 @@
+use std::sync::Arc;
+
+use axum::extract::FromRef;
+use reqwest::Client;
+use shared::retry::RetryPolicy;
+
+use crate::{auth::Authenticator, pool::Pools, ratelimit::RateLimiter, routes::RouteTable};
+
+#[derive(Clone, FromRef)]
+pub struct ProxyState {
+    pub client: Arc<Client>,
+    pub retry: Arc<RetryPolicy>,
+    pub routes: Arc<RouteTable>,
+    pub pools: Arc<Pools>,
+    pub limiter: Arc<RateLimiter>,
+    pub auth: Arc<Authenticator>,
+}
@@ -0,0 +1,348 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_OPEN_MS: u64 = 5000;
+const DEFAULT_HALF_OPEN_SUCCESSES: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Calls go through.
+    Closed,
+    /// Calls fail straight away until `open_for` has passed.
+    Open,
+    /// One probe call at a time goes through to see if the upstream is back.
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Consecutive failures that open a closed breaker.
+    pub failure_threshold: u32,
+    /// How long an open breaker waits before letting a probe through.
+    pub open_for: Duration,
+    /// Successful probes needed to close a half-open breaker.
+    pub half_open_successes: u32,
+}
+
+impl BreakerConfig {
+    /// Reads `BREAKER_FAILURE_THRESHOLD`, `BREAKER_OPEN_MS` and
+    /// `BREAKER_HALF_OPEN_SUCCESSES`.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(key: &str, default: T) -> T {
+            std::env::var(key)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            failure_threshold: var("BREAKER_FAILURE_THRESHOLD", DEFAULT_FAILURE_THRESHOLD).max(1),
+            open_for: Duration::from_millis(var("BREAKER_OPEN_MS", DEFAULT_OPEN_MS)),
+            half_open_successes: var("BREAKER_HALF_OPEN_SUCCESSES", DEFAULT_HALF_OPEN_SUCCESSES).max(1),
+        }
+    }
+}
+
+struct Inner {
+    state: BreakerState,
+    failures: u32,
+    successes: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+/// Circuit breaker for a single upstream.
+///
+/// Callers take a `Permit` before each call and report how it went; while
+/// the breaker is open `acquire` fails without touching the network.
+pub struct CircuitBreaker {
+    name: String,
+    config: BreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+/// Returned by `acquire` while the breaker is open.
+#[derive(Debug, Clone, Copy)]
+pub struct Open;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerSnapshot {
+    pub name: String,
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in_ms: Option<u64>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: BreakerConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            config,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                failures: 0,
+                successes: 0,
+                opened_at: None,
+                probing: false,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Asks to make a call, moving an open breaker to half-open once its
+    /// wait is over.
+    pub fn acquire(&self) -> Result<Permit<'_>, Open> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state == BreakerState::Open {
+            match inner.opened_at {
+                Some(opened_at) if opened_at.elapsed() < self.config.open_for => return Err(Open),
+                _ => {
+                    info!("circuit half-open: upstream={}", self.name);
+                    inner.state = BreakerState::HalfOpen;
+                    inner.successes = 0;
+                }
+            }
+        }
+
+        if inner.state == BreakerState::HalfOpen {
+            if inner.probing {
+                return Err(Open);
+            }
+
+            inner.probing = true;
+        }
+
+        Ok(Permit {
+            breaker: self,
+            probe: inner.state == BreakerState::HalfOpen,
+            done: false,
+        })
+    }
+
+    /// Whether a call would currently be let through, without making one.
+    pub fn allows(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen => !inner.probing,
+            BreakerState::Open => inner
+                .opened_at
+                .is_none_or(|opened_at| opened_at.elapsed() >= self.config.open_for),
+        }
+    }
+
+    pub fn snapshot(&self) -> BreakerSnapshot {
+        let inner = self.inner.lock().unwrap();
+
+        let retry_in_ms = match (inner.state, inner.opened_at) {
+            (BreakerState::Open, Some(opened_at)) => Some(
+                self.config
+                    .open_for
+                    .saturating_sub(opened_at.elapsed())
+                    .as_millis() as u64,
+            ),
+            _ => None,
+        };
+
+        BreakerSnapshot {
+            name: self.name.clone(),
+            state: inner.state,
+            consecutive_failures: inner.failures,
+            retry_in_ms,
+        }
+    }
+
+    fn on_success(&self, probe: bool) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.failures = 0;
+
+        if probe {
+            inner.probing = false;
+            inner.successes += 1;
+
+            if inner.successes >= self.config.half_open_successes {
+                info!("circuit closed: upstream={}", self.name);
+                inner.state = BreakerState::Closed;
+                inner.opened_at = None;
+            }
+        }
+    }
+
+    fn on_failure(&self, probe: bool) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.failures += 1;
+
+        if probe {
+            inner.probing = false;
+        }
+
+        let trip = match inner.state {
+            BreakerState::Closed => inner.failures >= self.config.failure_threshold,
+            BreakerState::HalfOpen => probe,
+            BreakerState::Open => false,
+        };
+
+        if trip {
+            warn!("circuit open: upstream={}, failures={}", self.name, inner.failures);
+            inner.state = BreakerState::Open;
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Permission to make one call. Dropping it without reporting, e.g. when
+/// the call is cancelled, counts as neither a success nor a failure.
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    done: bool,
+}
+
+impl Permit<'_> {
+    pub fn success(mut self) {
+        self.done = true;
+        self.breaker.on_success(self.probe);
+    }
+
+    pub fn failure(mut self) {
+        self.done = true;
+        self.breaker.on_failure(self.probe);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.done && self.probe {
+            self.breaker.inner.lock().unwrap().probing = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    const OPEN_FOR: Duration = Duration::from_millis(20);
+
+    fn breaker(failure_threshold: u32, half_open_successes: u32) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            BreakerConfig {
+                failure_threshold,
+                open_for: OPEN_FOR,
+                half_open_successes,
+            },
+        )
+    }
+
+    fn fail(breaker: &CircuitBreaker, times: u32) {
+        for _ in 0..times {
+            breaker.acquire().unwrap().failure();
+        }
+    }
+
+    fn state(breaker: &CircuitBreaker) -> BreakerState {
+        breaker.snapshot().state
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(3, 1);
+
+        fail(&breaker, 2);
+        breaker.acquire().unwrap().success();
+        fail(&breaker, 2);
+
+        assert_eq!(state(&breaker), BreakerState::Closed);
+
+        fail(&breaker, 1);
+
+        assert_eq!(state(&breaker), BreakerState::Open);
+        assert!(!breaker.allows());
+        assert!(breaker.acquire().is_err());
+        assert!(breaker.snapshot().retry_in_ms.is_some());
+    }
+
+    #[test]
+    fn lets_one_probe_through_once_open_for_has_passed() {
+        let breaker = breaker(1, 1);
+
+        fail(&breaker, 1);
+        sleep(OPEN_FOR);
+
+        assert!(breaker.allows());
+
+        let probe = breaker.acquire().unwrap();
+
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+        assert!(!breaker.allows());
+        assert!(breaker.acquire().is_err());
+
+        probe.success();
+
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        assert!(breaker.allows());
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = breaker(1, 1);
+
+        fail(&breaker, 1);
+        sleep(OPEN_FOR);
+        fail(&breaker, 1);
+
+        assert_eq!(state(&breaker), BreakerState::Open);
+        assert!(breaker.acquire().is_err());
+    }
+
+    #[test]
+    fn closes_after_enough_probe_successes() {
+        let breaker = breaker(1, 2);
+
+        fail(&breaker, 1);
+        sleep(OPEN_FOR);
+
+        breaker.acquire().unwrap().success();
+
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+
+        breaker.acquire().unwrap().success();
+
+        assert_eq!(state(&breaker), BreakerState::Closed);
+    }
+
+    #[test]
+    fn dropped_probe_frees_the_slot() {
+        let breaker = breaker(1, 1);
+
+        fail(&breaker, 1);
+        sleep(OPEN_FOR);
+
+        drop(breaker.acquire().unwrap());
+
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+        assert!(breaker.allows());
+
+        breaker.acquire().unwrap().success();
+
+        assert_eq!(state(&breaker), BreakerState::Closed);
+    }
+}
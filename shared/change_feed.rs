@@ -0,0 +1,154 @@
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+use tracing::{error, warn};
+
+use crate::{header_helper::get_header_blocking, state::change::ChangeEvent};
+
+pub const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+/// Served by the watch route under each resource, e.g. `GET /entity/watch`,
+/// which shadows an item of the same name.
+pub const WATCH_NAME: &str = "watch";
+
+/// Refuses a name the microservices' watch routes shadow, for callers that
+/// would otherwise store an item they can't read back.
+pub fn check_name(name: &str) -> Result<(), StatusCode> {
+    match name == WATCH_NAME {
+        true => {
+            error!("resp: status={}, reserved name={name}", StatusCode::BAD_REQUEST);
+            Err(StatusCode::BAD_REQUEST)
+        }
+        false => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WatchQuery {
+    pub since: Option<u64>,
+    pub prefix: Option<String>,
+}
+
+impl WatchQuery {
+    /// The version to resume after, preferring the `Last-Event-ID` an
+    /// `EventSource` sends when it reconnects.
+    pub fn resume_from(&self, headers: &HeaderMap) -> Option<u64> {
+        get_header_blocking(headers, LAST_EVENT_ID_HEADER)
+            .and_then(|id| id.parse().ok())
+            .or(self.since)
+    }
+}
+
+/// Streams `backlog` followed by live changes as Server-Sent Events, with the
+/// version as the event id.
+///
+/// A watcher that falls behind the broadcast channel has its stream closed;
+/// it can reconnect with `Last-Event-ID` to pick up from the retained history.
+pub fn change_stream<T>(
+    backlog: Vec<ChangeEvent<T>>,
+    receiver: broadcast::Receiver<ChangeEvent<T>>,
+    prefix: Option<String>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>>
+where
+    T: Serialize + Clone + Send + 'static,
+{
+    let live = BroadcastStream::new(receiver).map_while(|event| match event {
+        Ok(event) => Some(event),
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            warn!("watcher lagged, closing stream: skipped={skipped}");
+            None
+        }
+    });
+
+    let events = tokio_stream::iter(backlog)
+        .chain(live)
+        .filter(move |event| match &prefix {
+            Some(prefix) => event.key.starts_with(prefix.as_str()),
+            None => true,
+        })
+        .map(|event| {
+            Event::default()
+                .id(event.version.to_string())
+                .event("change")
+                .json_data(&event)
+        });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// One event read by `ChangeFeedReader`.
+#[derive(Debug, Clone, Default)]
+pub struct FeedEvent {
+    pub id: Option<u64>,
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Reads a stream served by `change_stream` from another service, one event
+/// at a time.
+pub struct ChangeFeedReader {
+    response: reqwest::Response,
+    buffer: String,
+}
+
+impl ChangeFeedReader {
+    pub fn new(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            buffer: String::new(),
+        }
+    }
+
+    /// The next event, or `None` once the stream ends. Keep-alive comments
+    /// are skipped.
+    pub async fn next(&mut self) -> Result<Option<FeedEvent>, reqwest::Error> {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let block: String = self.buffer.drain(..end + 2).collect();
+
+                if let Some(event) = parse_event(&block) {
+                    return Ok(Some(event));
+                }
+
+                continue;
+            }
+
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.push_str(&String::from_utf8_lossy(&chunk).replace("\r\n", "\n")),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+fn parse_event(block: &str) -> Option<FeedEvent> {
+    let mut event = FeedEvent::default();
+    let mut data = Vec::new();
+
+    for line in block.lines() {
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+
+        match field {
+            "id" => event.id = value.parse().ok(),
+            "event" => event.event = Some(value.to_string()),
+            "data" => data.push(value),
+            _ => {}
+        }
+    }
+
+    if data.is_empty() {
+        return None;
+    }
+
+    event.data = data.join("\n");
+
+    Some(event)
+}
@@ -0,0 +1,437 @@
+use std::{
+    fmt::{Display, Formatter},
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use axum::http::StatusCode;
+use reqwest::{Client, RequestBuilder, Response};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    breaker::CircuitBreaker,
+    change_feed::ChangeFeedReader,
+    context::{current_deadline, current_logid, current_principal, remaining},
+    header_helper::{DEADLINE_HEADER, LOGID_HEADER, PRINCIPAL_HEADER},
+    prelude::generate_trace_id,
+    registry::ServiceRegistry,
+    retry::{is_retryable_error, is_retryable_status, RetryPolicy},
+    state::{
+        entity::Entity,
+        property::Property,
+        query::{BatchGet, BatchResult, ListQuery, Page},
+        version::{parse_etag, Precondition, Versioned},
+    },
+};
+
+pub type EntityClient = ResourceClient<Entity>;
+pub type PropertyClient = ResourceClient<Property>;
+
+#[derive(Debug)]
+pub enum ClientError {
+    NotFound,
+    /// The service refused the request, e.g. a bad list query or a failed
+    /// precondition.
+    Rejected(reqwest::StatusCode),
+    /// The service failed to handle the request.
+    Upstream(reqwest::StatusCode),
+    /// No response came back.
+    Transport(reqwest::Error),
+    /// The response body wasn't the expected type.
+    Decode(reqwest::Error),
+    /// The upstream's circuit breaker is open, so no request was sent.
+    CircuitOpen,
+    /// The registry has no healthy endpoint for the service.
+    NoEndpoint,
+    /// The current request's deadline passed before the call was made.
+    DeadlineExceeded,
+}
+
+impl ClientError {
+    /// Whether sending the same request again might succeed, by the same
+    /// rules as the proxy's `retry::should_retry`.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::Upstream(status) => is_retryable_status(status.as_u16()),
+            ClientError::Transport(e) => is_retryable_error(e),
+            _ => false,
+        }
+    }
+
+    /// Whether the request may have taken effect anyway: anything but an
+    /// answer saying it didn't, or a call that was never sent.
+    pub fn may_have_applied(&self) -> bool {
+        !matches!(
+            self,
+            ClientError::NotFound | ClientError::Rejected(_) | ClientError::CircuitOpen | ClientError::NoEndpoint
+        )
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::NotFound => write!(f, "not found"),
+            ClientError::Rejected(status) => write!(f, "request rejected: status_code={status}"),
+            ClientError::Upstream(status) => write!(f, "upstream failed: status_code={status}"),
+            ClientError::Transport(e) => write!(f, "error sending request: error={e}"),
+            ClientError::Decode(e) => write!(f, "error parsing response: error={e}"),
+            ClientError::CircuitOpen => write!(f, "circuit open"),
+            ClientError::NoEndpoint => write!(f, "no healthy endpoint"),
+            ClientError::DeadlineExceeded => write!(f, "deadline exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<ClientError> for StatusCode {
+    fn from(e: ClientError) -> Self {
+        StatusCode::from(&e)
+    }
+}
+
+/// Passes rejections through to the caller; anything else is our failure.
+impl From<&ClientError> for StatusCode {
+    fn from(e: &ClientError) -> Self {
+        match e {
+            ClientError::NotFound => StatusCode::NOT_FOUND,
+            ClientError::CircuitOpen | ClientError::NoEndpoint => StatusCode::SERVICE_UNAVAILABLE,
+            ClientError::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            ClientError::Rejected(status) => {
+                StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::BAD_REQUEST)
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Typed client for a resource served by one of the microservices, e.g.
+/// `/entity/:name` on entity_microservice. Each call goes to the next of the
+/// service's healthy endpoints in the registry.
+///
+/// Every request carries the logid of the current request, see
+/// `context::current_logid`, its principal, and what is left of its deadline.
+/// With a breaker attached, transport errors and server errors count against
+/// it and calls fail fast while it is open. With a retry policy attached,
+/// reads and deletes are retried on those errors.
+pub struct ResourceClient<T> {
+    client: Arc<Client>,
+    registry: Arc<ServiceRegistry>,
+    service: String,
+    next: Arc<AtomicUsize>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    retry: Option<Arc<RetryPolicy>>,
+    resource: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for ResourceClient<T> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            registry: self.registry.clone(),
+            service: self.service.clone(),
+            next: self.next.clone(),
+            breaker: self.breaker.clone(),
+            retry: self.retry.clone(),
+            resource: PhantomData,
+        }
+    }
+}
+
+impl EntityClient {
+    /// `/entity` on the registry's "entity" service.
+    pub fn from_registry(client: Arc<Client>, registry: Arc<ServiceRegistry>) -> Self {
+        Self::new(client, registry, "entity")
+    }
+}
+
+impl PropertyClient {
+    /// `/property` on the registry's "property" service.
+    pub fn from_registry(client: Arc<Client>, registry: Arc<ServiceRegistry>) -> Self {
+        Self::new(client, registry, "property")
+    }
+}
+
+impl<T> ResourceClient<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// `service` names both the service in `registry` and the resource it
+    /// serves, e.g. "entity".
+    pub fn new(client: Arc<Client>, registry: Arc<ServiceRegistry>, service: &str) -> Self {
+        Self {
+            client,
+            registry,
+            service: service.to_string(),
+            next: Arc::new(AtomicUsize::new(0)),
+            breaker: None,
+            retry: None,
+            resource: PhantomData,
+        }
+    }
+
+    pub fn with_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Arc<RetryPolicy>) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_deref()
+    }
+
+    /// Whether a call would be sent right now rather than failing fast.
+    pub fn available(&self) -> bool {
+        self.breaker().is_none_or(CircuitBreaker::allows)
+    }
+
+    pub async fn get(&self, name: &str) -> Result<T, ClientError> {
+        let response = self.send(|base| self.client.get(format!("{base}/{name}")), true).await?;
+
+        response.json().await.map_err(ClientError::Decode)
+    }
+
+    /// Like `get`, but a missing resource is `None` rather than an error.
+    pub async fn find(&self, name: &str) -> Result<Option<T>, ClientError> {
+        match self.get(name).await {
+            Ok(value) => Ok(Some(value)),
+            Err(ClientError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like `find`, along with the version from the resource's ETag.
+    pub async fn find_versioned(&self, name: &str) -> Result<Option<Versioned<T>>, ClientError> {
+        let response = match self.send(|base| self.client.get(format!("{base}/{name}")), true).await {
+            Ok(response) => response,
+            Err(ClientError::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let version = version(&response).ok_or(ClientError::Upstream(reqwest::StatusCode::BAD_GATEWAY))?;
+
+        Ok(Some(Versioned::new(version, response.json().await.map_err(ClientError::Decode)?)))
+    }
+
+    pub async fn list(&self, query: &ListQuery) -> Result<Page<T>, ClientError> {
+        let params = query.to_params();
+
+        let response = self.send(|base| self.client.get(base).query(&params), true).await?;
+
+        response.json().await.map_err(ClientError::Decode)
+    }
+
+    /// Fetches every name in one call. Safe to retry, as it only reads.
+    pub async fn batch_get(&self, names: &[String]) -> Result<BatchResult<T>, ClientError> {
+        let batch = BatchGet { names: names.to_vec() };
+
+        let response = self.send(|base| self.client.post(format!("{base}:batchGet")).json(&batch), true).await?;
+
+        response.json().await.map_err(ClientError::Decode)
+    }
+
+    /// Creates or replaces `name`. Not retried: a POST that failed may
+    /// still have landed, and a write since then would be overwritten.
+    pub async fn set(&self, name: &str, value: &T) -> Result<(), ClientError> {
+        self.set_if(name, value, &Precondition::none()).await.map(|_| ())
+    }
+
+    /// Like `set`, but only if `precondition` holds for what's stored; a
+    /// failed one is `Rejected` with 412. Returns the version written, if
+    /// the service said.
+    pub async fn set_if(&self, name: &str, value: &T, precondition: &Precondition) -> Result<Option<u64>, ClientError> {
+        let response = self
+            .send(|base| conditional(self.client.post(format!("{base}/{name}")).json(value), precondition), false)
+            .await?;
+
+        Ok(version(&response))
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), ClientError> {
+        self.delete_if(name, &Precondition::none()).await
+    }
+
+    /// Like `delete`, but only if `precondition` holds for what's stored.
+    pub async fn delete_if(&self, name: &str, precondition: &Precondition) -> Result<(), ClientError> {
+        self.send(|base| conditional(self.client.delete(format!("{base}/{name}")), precondition), true).await?;
+
+        Ok(())
+    }
+
+    /// Follows the resource's change feed, starting after version `since`.
+    ///
+    /// The feed is long-lived, so it goes around the breaker, retries and
+    /// request deadline.
+    pub async fn watch(&self, since: Option<u64>) -> Result<ChangeFeedReader, ClientError> {
+        let mut request = self
+            .client
+            .get(format!("{}/watch", self.base().await?))
+            .header(LOGID_HEADER, current_logid().unwrap_or_else(generate_trace_id));
+
+        if let Some(since) = since {
+            request = request.query(&[("since", since)]);
+        }
+
+        let response = request.send().await.map_err(ClientError::Transport)?;
+
+        match response.status() {
+            status if status.is_success() => Ok(ChangeFeedReader::new(response)),
+            status if status.is_client_error() => Err(ClientError::Rejected(status)),
+            status => Err(ClientError::Upstream(status)),
+        }
+    }
+
+    /// The resource on the next healthy endpoint, e.g.
+    /// `http://entity_microservice:8081/entity`.
+    async fn base(&self) -> Result<String, ClientError> {
+        let endpoints = self.registry.endpoints(&self.service).await;
+
+        if endpoints.is_empty() {
+            error!("no healthy endpoint: service={}", self.service);
+            return Err(ClientError::NoEndpoint);
+        }
+
+        let endpoint = &endpoints[self.next.fetch_add(1, Ordering::Relaxed) % endpoints.len()];
+
+        Ok(format!("{}/{}", endpoint.url(), self.service))
+    }
+
+    /// Sends the request `request` builds for the resource's base URL,
+    /// picking the endpoint again for each attempt.
+    async fn send<F>(&self, request: F, idempotent: bool) -> Result<Response, ClientError>
+    where
+        F: Fn(&str) -> RequestBuilder,
+    {
+        match &self.retry {
+            Some(retry) => {
+                retry
+                    .run(idempotent, || self.attempt(&request), |result| {
+                        matches!(result, Err(e) if e.is_retryable())
+                    })
+                    .await
+            }
+            None => self.attempt(&request).await,
+        }
+    }
+
+    async fn attempt<F>(&self, request: &F) -> Result<Response, ClientError>
+    where
+        F: Fn(&str) -> RequestBuilder,
+    {
+        if remaining().is_some_and(|remaining| remaining.is_zero()) {
+            return Err(ClientError::DeadlineExceeded);
+        }
+
+        let request = request(&self.base().await?);
+
+        let permit = match self.breaker() {
+            Some(breaker) => match breaker.acquire() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    warn!("circuit open, not sending: upstream={}", breaker.name());
+                    return Err(ClientError::CircuitOpen);
+                }
+            },
+            None => None,
+        };
+
+        let result = self.send_inner(request).await;
+
+        if let Some(permit) = permit {
+            match &result {
+                // The request running out of time says nothing about the
+                // upstream.
+                Err(ClientError::DeadlineExceeded) => drop(permit),
+                Err(ClientError::Upstream(_) | ClientError::Transport(_)) => permit.failure(),
+                _ => permit.success(),
+            }
+        }
+
+        result
+    }
+
+    async fn send_inner(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        let logid = current_logid().unwrap_or_else(generate_trace_id);
+
+        let send = propagate_principal(propagate_deadline(request))
+            .header(LOGID_HEADER, logid)
+            .send();
+
+        // Only waiting for the response headers is bound by the deadline.
+        let sent = match current_deadline() {
+            Some(deadline) => tokio::time::timeout_at(deadline, send).await.map_err(|_| {
+                error!("deadline exceeded waiting for response: service={}", self.service);
+                ClientError::DeadlineExceeded
+            })?,
+            None => send.await,
+        };
+
+        let response = sent.map_err(|e| {
+            error!("error requesting: service={}, error={:?}", self.service, e);
+            ClientError::Transport(e)
+        })?;
+
+        info!("response={:?}", response);
+
+        let status = response.status();
+
+        match status {
+            _ if status.is_success() => Ok(response),
+            reqwest::StatusCode::NOT_FOUND => Err(ClientError::NotFound),
+            _ if status.is_client_error() => {
+                warn!("request rejected: url={}, status_code={}", response.url(), status);
+                Err(ClientError::Rejected(status))
+            }
+            _ => {
+                error!("unexpected status code: url={}, status_code={}", response.url(), status);
+                Err(ClientError::Upstream(status))
+            }
+        }
+    }
+}
+
+/// Adds the `If-Match`/`If-None-Match` headers for `precondition`.
+fn conditional(request: RequestBuilder, precondition: &Precondition) -> RequestBuilder {
+    precondition
+        .to_headers()
+        .into_iter()
+        .fold(request, |request, (name, value)| request.header(name.as_str(), value))
+}
+
+/// The version in the response's ETag.
+fn version(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .and_then(parse_etag)
+}
+
+/// Passes what is left of the current deadline on to the next service.
+///
+/// The request itself isn't given a timeout, as that would also cut off
+/// reading the body: callers stop waiting for the response headers at the
+/// deadline, and let a streamed body, e.g. a change feed, run on past it.
+pub fn propagate_deadline(request: RequestBuilder) -> RequestBuilder {
+    match remaining() {
+        Some(remaining) => request.header(DEADLINE_HEADER, remaining.as_millis().to_string()),
+        None => request,
+    }
+}
+
+/// Makes the next service's request on behalf of the same principal.
+pub fn propagate_principal(request: RequestBuilder) -> RequestBuilder {
+    match current_principal() {
+        Some(principal) => request.header(PRINCIPAL_HEADER, principal),
+        None => request,
+    }
+}
@@ -0,0 +1,50 @@
+use std::{future::Future, time::Duration};
+
+use tokio::time::Instant;
+
+tokio::task_local! {
+    static LOGID: String;
+}
+
+/// Runs `f` with `logid` available to everything it calls through
+/// `current_logid`, without threading it through every signature.
+pub async fn scope_logid<F: Future>(logid: String, f: F) -> F::Output {
+    LOGID.scope(logid, f).await
+}
+
+pub fn current_logid() -> Option<String> {
+    LOGID.try_with(|logid| logid.clone()).ok()
+}
+
+tokio::task_local! {
+    static PRINCIPAL: Option<String>;
+}
+
+/// Runs `f` with the request's principal, if it has one, available through
+/// `current_principal`.
+pub async fn scope_principal<F: Future>(principal: Option<String>, f: F) -> F::Output {
+    PRINCIPAL.scope(principal, f).await
+}
+
+pub fn current_principal() -> Option<String> {
+    PRINCIPAL.try_with(|principal| principal.clone()).ok().flatten()
+}
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+/// Runs `f` with `deadline` available through `current_deadline`, so calls
+/// to other services can pass on what is left of it.
+pub async fn scope_deadline<F: Future>(deadline: Instant, f: F) -> F::Output {
+    DEADLINE.scope(deadline, f).await
+}
+
+pub fn current_deadline() -> Option<Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+/// Time left before the current deadline, if there is one.
+pub fn remaining() -> Option<Duration> {
+    current_deadline().map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
@@ -0,0 +1,278 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rdkafka::{
+    config::FromClientConfig,
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig,
+};
+use serde::Serialize;
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+use tracing::{error, info, warn};
+
+use crate::state::{change::ChangeEvent, AppState, StateError};
+
+const SEND_TIMEOUT: Duration = Duration::from_millis(1000);
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_millis(5000);
+
+/// Destination for domain events, keyed by resource name.
+pub trait EventSink: Send + Sync + 'static {
+    fn publish(&self, key: &str, payload: &str) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+}
+
+/// Publishes to a Kafka topic through the same `FutureProducer` setup as
+/// logging_processor.
+pub struct KafkaSink {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl KafkaSink {
+    pub fn new(bootstrap_servers: &str, topic: &str) -> Result<Self, anyhow::Error> {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", bootstrap_servers);
+
+        Ok(Self {
+            producer: FutureProducer::from_config(&config)?,
+            topic: topic.to_string(),
+        })
+    }
+
+    /// Reads `KAFKA_BOOTSTRAP_SERVERS` and `KAFKA_CHANGE_TOPIC`, returning
+    /// `None` when no brokers are configured.
+    pub fn from_env(default_topic: &str) -> Result<Option<Self>, anyhow::Error> {
+        let bootstrap_servers = match std::env::var("KAFKA_BOOTSTRAP_SERVERS") {
+            Ok(bootstrap_servers) => bootstrap_servers,
+            Err(_) => {
+                warn!("KAFKA_BOOTSTRAP_SERVERS not set, not publishing change events");
+                return Ok(None);
+            }
+        };
+
+        let topic = std::env::var("KAFKA_CHANGE_TOPIC").unwrap_or(default_topic.to_string());
+
+        info!("publishing change events: bootstrap_servers={bootstrap_servers}, topic={topic}");
+
+        Self::new(&bootstrap_servers, &topic).map(Some)
+    }
+}
+
+impl EventSink for KafkaSink {
+    async fn publish(&self, key: &str, payload: &str) -> Result<(), anyhow::Error> {
+        let record: FutureRecord<'_, str, str> = FutureRecord::to(&self.topic)
+            .key(key)
+            .payload(payload);
+
+        self.producer
+            .send(record, SEND_TIMEOUT)
+            .await
+            .map_err(|(e, _)| e)?;
+
+        Ok(())
+    }
+}
+
+/// Keeps published events in memory, for running without a broker and in
+/// tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    records: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `(key, payload)` pairs in the order they were published.
+    pub fn records(&self) -> Vec<(String, String)> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+impl EventSink for MemorySink {
+    async fn publish(&self, key: &str, payload: &str) -> Result<(), anyhow::Error> {
+        self.records
+            .lock()
+            .unwrap()
+            .push((key.to_string(), payload.to_string()));
+
+        Ok(())
+    }
+}
+
+/// A change to a resource, e.g. `{"type": "EntityChanged", "key": ...}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DomainEvent<'a, T> {
+    #[serde(rename = "type")]
+    pub event_type: &'a str,
+    #[serde(flatten)]
+    pub change: &'a ChangeEvent<T>,
+}
+
+/// Forwards every change to `state` to `sink` as an `event_type` event.
+///
+/// Events are published one at a time so each key's events stay in order. A
+/// failed publish is retried, backing off up to `MAX_RETRY_DELAY`, before
+/// any later event is sent; meanwhile new changes wait in the state's change
+/// history, which the publisher catches up from. Delivery is at least once
+/// (a send that timed out may have landed) as long as the sink recovers
+/// before `CHANGE_HISTORY` more changes are made; past that the skipped
+/// events are logged as lost.
+pub async fn spawn_publisher<T, S>(state: AppState<T>, sink: S, event_type: &'static str) -> Result<JoinHandle<()>, StateError>
+where
+    T: Serialize + Clone + std::fmt::Display + Send + 'static,
+    S: EventSink,
+{
+    let since = state.version().await;
+    let (mut backlog, mut receiver) = state.watch(Some(since)).await?;
+
+    Ok(tokio::spawn(async move {
+        let mut last_version = since;
+
+        backlog.reverse();
+
+        loop {
+            let change = match backlog.pop() {
+                Some(change) => change,
+                None => match receiver.recv().await {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("publisher lagged, catching up: skipped={skipped}");
+
+                        match state.watch(Some(last_version)).await {
+                            Ok((mut missed, resubscribed)) => {
+                                missed.reverse();
+                                backlog = missed;
+                                receiver = resubscribed;
+                            }
+                            Err(e) => error!("unable to catch up, events lost: error={e}"),
+                        }
+
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            };
+
+            last_version = change.version;
+
+            let payload = match serde_json::to_string(&DomainEvent { event_type, change: &change }) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!("error serializing event: change={change}, error={e}");
+                    continue;
+                }
+            };
+
+            publish(&sink, &change.key, change.version, &payload).await;
+        }
+
+        info!("change feed closed, stopping publisher");
+    }))
+}
+
+/// Publishes `payload`, retrying until `sink` takes it.
+async fn publish<S: EventSink>(sink: &S, key: &str, version: u64, payload: &str) {
+    let mut delay = RETRY_DELAY;
+
+    while let Err(e) = sink.publish(key, payload).await {
+        warn!("error publishing event, retrying: key={key}, version={version}, delay_ms={}, error={e}", delay.as_millis());
+
+        tokio::time::sleep(delay).await;
+
+        delay = delay.saturating_mul(2).min(MAX_RETRY_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::state::version::Precondition;
+
+    /// Fails the first `failures` publishes, then hands them to `inner`.
+    struct FlakySink {
+        failures: AtomicU32,
+        inner: MemorySink,
+    }
+
+    impl EventSink for FlakySink {
+        async fn publish(&self, key: &str, payload: &str) -> Result<(), anyhow::Error> {
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(anyhow!("broker unavailable"));
+            }
+
+            self.inner.publish(key, payload).await
+        }
+    }
+
+    async fn published(sink: &MemorySink, count: usize) -> Vec<(String, String)> {
+        for _ in 0..100 {
+            if sink.records().len() >= count {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        sink.records()
+    }
+
+    fn versions(records: &[(String, String)]) -> Vec<(String, u64)> {
+        records
+            .iter()
+            .map(|(key, payload)| {
+                let event: serde_json::Value = serde_json::from_str(payload).unwrap();
+
+                assert_eq!(event["type"], "ThingChanged");
+
+                (key.clone(), event["version"].as_u64().unwrap())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn publishes_changes_in_order() {
+        let state = AppState::<String>::new();
+        let sink = MemorySink::new();
+
+        spawn_publisher(state.clone(), sink.clone(), "ThingChanged").await.unwrap();
+
+        state.set("a", &"1".to_string(), &Precondition::none()).await.unwrap();
+        state.set("b", &"1".to_string(), &Precondition::none()).await.unwrap();
+        state.set("a", &"2".to_string(), &Precondition::none()).await.unwrap();
+
+        assert_eq!(
+            versions(&published(&sink, 3).await),
+            [("a".to_string(), 1), ("b".to_string(), 2), ("a".to_string(), 3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_failed_publishes_before_later_events() {
+        let state = AppState::<String>::new();
+        let memory = MemorySink::new();
+        let sink = FlakySink {
+            failures: AtomicU32::new(2),
+            inner: memory.clone(),
+        };
+
+        spawn_publisher(state.clone(), sink, "ThingChanged").await.unwrap();
+
+        state.set("a", &"1".to_string(), &Precondition::none()).await.unwrap();
+        state.set("a", &"2".to_string(), &Precondition::none()).await.unwrap();
+
+        assert_eq!(
+            versions(&published(&memory, 2).await),
+            [("a".to_string(), 1), ("a".to_string(), 2)]
+        );
+    }
+}
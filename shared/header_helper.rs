@@ -1,9 +1,19 @@
-use axum::http::HeaderMap;
+use std::time::Duration;
+
+use axum::http::{header, HeaderMap};
 
 use crate::prelude::generate_trace_id;
 
 pub const LOGID_HEADER: &str = "logid";
 
+/// Milliseconds the caller is still prepared to wait for a response.
+pub const DEADLINE_HEADER: &str = "x-deadline-ms";
+
+/// Who the request is made for, as authenticated by proxy_handler, which
+/// drops any the client sent. Only trustworthy where the services can't be
+/// reached except through the proxy.
+pub const PRINCIPAL_HEADER: &str = "x-principal";
+
 pub fn set_header_logid(headers: &mut HeaderMap, logid: String) {
     headers.insert(LOGID_HEADER, logid.parse().unwrap());
 }
@@ -18,6 +28,12 @@ pub async fn get_logid(headers: HeaderMap) -> String {
     get_header(headers, LOGID_HEADER).await.unwrap_or(generate_trace_id())
 }
 
+pub fn get_header_blocking(headers: &HeaderMap, key: &str) -> Option<String> {
+    headers.get(key)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
 pub async fn get_header(headers: HeaderMap, key: &str) -> Option<String> {
     match headers.get(key) {
         Some(value) => {
@@ -29,4 +45,18 @@ pub async fn get_header(headers: HeaderMap, key: &str) -> Option<String> {
         None => None,
     }
 
-}
\ No newline at end of file
+}
+
+pub fn set_header_etag(headers: &mut HeaderMap, etag: &str) {
+    headers.insert(header::ETAG, etag.parse().unwrap());
+}
+
+pub fn get_deadline_blocking(headers: &HeaderMap) -> Option<Duration> {
+    get_header_blocking(headers, DEADLINE_HEADER)
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_millis)
+}
+
+pub fn get_principal_blocking(headers: &HeaderMap) -> Option<String> {
+    get_header_blocking(headers, PRINCIPAL_HEADER)
+}
@@ -1,13 +1,10 @@
-use std::{fmt::Display, sync::Arc, net::SocketAddr};
+use std::{fmt::Display, sync::Arc};
 
-use axum::{Router, extract::{Request, ConnectInfo}};
-use http_body_util::Full;
-use hyper::body::Bytes;
-use tower_http::trace::TraceLayer;
+use axum::{middleware, Router};
 use tracing::info;
 use tracing_subscriber::prelude::*;
 
-use crate::{state::AppState, util_router, header_helper::{get_logid, get_logid_blocking}, layer::{tracing_layer, logid_layer}};
+use crate::{state::AppState, util_router, layer::{tracing_layer, logid_layer, logid_scope, deadline_scope, principal_scope}};
 
 pub fn init_tracing() {
     let filter_layer = tracing_subscriber::filter::LevelFilter::INFO;
@@ -26,23 +23,22 @@ pub fn init_tracing() {
     tracing::subscriber::set_global_default(subscriber).unwrap();
 }
 
-pub async fn start_server<T>(router: Router<Arc<AppState<T>>>) -> Result<(), anyhow::Error>
+pub async fn start_server<T>(router: Router<Arc<AppState<T>>>, app_state: AppState<T>) -> Result<(), anyhow::Error>
 where
 T: Clone + Display + Send + Sync + 'static
 {
-    init_tracing();
-
     info!("Starting microservice");
 
-    info!("Creating app_state");
-
-    let app_state = Arc::new(AppState::<T>::new());
+    let app_state = Arc::new(app_state);
 
     info!("Creating routers");
 
     let router = Router::new()
         .merge(util_router::get_router())
         .merge(router)
+        .layer(middleware::from_fn(deadline_scope))
+        .layer(middleware::from_fn(principal_scope))
+        .layer(middleware::from_fn(logid_scope))
         .layer(tracing_layer())
         .layer(logid_layer())
         .with_state(app_state.clone());
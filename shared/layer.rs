@@ -1,13 +1,22 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use axum::{
     body::Body,
-    extract::{ConnectInfo, Request}, http::{HeaderName, HeaderValue},
+    extract::{ConnectInfo, Request}, http::{HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
+use tokio::time::Instant;
 use tower_http::{classify::{ServerErrorsAsFailures, SharedClassifier}, set_header::SetRequestHeaderLayer};
-use tracing::Span;
+use tracing::{error, Span};
 
-use crate::{header_helper::{get_logid_blocking, LOGID_HEADER}, prelude::generate_trace_id};
+use crate::{
+    context::{scope_deadline, scope_logid, scope_principal},
+    header_helper::{get_deadline_blocking, get_logid_blocking, get_principal_blocking, LOGID_HEADER},
+    prelude::generate_trace_id,
+};
+
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 10000;
 
 type TraceLayer = tower_http::trace::TraceLayer<SharedClassifier<ServerErrorsAsFailures>, fn(&hyper::Request<Body>) -> Span>;
 type LogidLayer<T> = tower_http::set_header::SetRequestHeaderLayer<for<'a> fn(&'a T) -> Option<HeaderValue>>;
@@ -21,6 +30,61 @@ pub fn logid_layer<T>() -> LogidLayer<T> {
     SetRequestHeaderLayer::if_not_present(HeaderName::from_static(LOGID_HEADER), generate_trace_id_for_layer)
 }
 
+/// Makes the request's logid available to `context::current_logid` for the
+/// rest of the request. Use with `axum::middleware::from_fn`, inside
+/// `logid_layer`.
+pub async fn logid_scope(request: Request, next: Next) -> Response {
+    let logid = get_logid_blocking(request.headers());
+
+    scope_logid(logid, next.run(request)).await
+}
+
+/// Makes the principal proxy_handler authenticated available to
+/// `context::current_principal`, and records it on the request's span. Use
+/// with `axum::middleware::from_fn`, inside `tracing_layer`.
+pub async fn principal_scope(request: Request, next: Next) -> Response {
+    let principal = get_principal_blocking(request.headers());
+
+    if let Some(principal) = &principal {
+        Span::current().record("principal", principal.as_str());
+    }
+
+    scope_principal(principal, next.run(request)).await
+}
+
+/// Gives the request until the deadline in its `DEADLINE_HEADER`, or
+/// `REQUEST_TIMEOUT_MS` without one, answering 504 once it passes. The
+/// deadline is available to `context::current_deadline` meanwhile. Use with
+/// `axum::middleware::from_fn`.
+pub async fn deadline_scope(request: Request, next: Next) -> Response {
+    let budget = get_deadline_blocking(request.headers()).unwrap_or_else(default_request_timeout);
+
+    enforce_deadline(Instant::now() + budget, request, next).await
+}
+
+/// Runs the rest of the request under `deadline`, answering 504 once it
+/// passes.
+pub async fn enforce_deadline(deadline: Instant, request: Request, next: Next) -> Response {
+    let response = scope_deadline(deadline, tokio::time::timeout_at(deadline, next.run(request))).await;
+
+    match response {
+        Ok(response) => response,
+        Err(_) => {
+            error!("resp: status=504, deadline exceeded");
+            StatusCode::GATEWAY_TIMEOUT.into_response()
+        }
+    }
+}
+
+fn default_request_timeout() -> Duration {
+    let timeout = std::env::var("REQUEST_TIMEOUT_MS")
+        .ok()
+        .and_then(|timeout| timeout.parse().ok())
+        .unwrap_or(DEFAULT_REQUEST_TIMEOUT_MS);
+
+    Duration::from_millis(timeout)
+}
+
 fn trace_layer_inner(request: &Request) -> Span {
     let caller = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
         Some(addr) => addr.to_string(),
@@ -34,7 +98,8 @@ fn trace_layer_inner(request: &Request) -> Span {
         logid = %logid,
         method = %request.method(),
         uri = %request.uri(),
-        caller
+        caller,
+        principal = tracing::field::Empty
     )
 }
 
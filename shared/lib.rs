@@ -5,6 +5,13 @@ pub mod state;
 pub mod result;
 pub mod header_helper;
 pub mod layer;
+pub mod context;
+pub mod change_feed;
+pub mod events;
+pub mod client;
+pub mod breaker;
+pub mod retry;
+pub mod registry;
 
 pub mod prelude {
     pub use crate::init::init_tracing;
@@ -0,0 +1,447 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use hickory_resolver::TokioAsyncResolver;
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_FILE: &str = "services.toml";
+const DEFAULT_REFRESH_MS: u64 = 5000;
+
+/// One address a service can be reached at.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Endpoint {
+    /// Parses `host` or `host:port`, with IPv6 addresses in brackets when
+    /// they have a port, e.g. `[::1]:8080`.
+    pub fn parse(address: &str, default_port: u16) -> Option<Self> {
+        let address = address.trim();
+
+        let (host, port) = match address.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']')? {
+                (host, "") => (host, default_port),
+                (host, port) => (host, port.strip_prefix(':')?.parse().ok()?),
+            },
+            None => match address.rsplit_once(':') {
+                // More than one colon is an IPv6 address without a port.
+                Some((host, _)) if host.contains(':') => (address, default_port),
+                Some((host, port)) => (host, port.parse().ok()?),
+                None => (address, default_port),
+            },
+        };
+
+        match host.is_empty() {
+            true => None,
+            false => Some(Self {
+                host: host.to_string(),
+                port,
+            }),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{self}")
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// Where a `ServiceRegistry` finds a service's endpoints.
+#[derive(Debug, Clone)]
+pub enum Discovery {
+    /// `{SERVICE}_ADDRESS`, a comma separated list of `host[:port]`, with
+    /// `{SERVICE}_PORT` as the default port.
+    Env,
+    /// A TOML file, or JSON if it ends in `.json`, listing `host:port`
+    /// endpoints under `[services]`, reloaded whenever it changes.
+    File(PathBuf),
+    /// The SRV records named by `{SERVICE}_SRV`, or failing that every address
+    /// the `Env` host resolves to.
+    Dns,
+}
+
+impl Discovery {
+    /// Reads `DISCOVERY` (`env`, `file` or `dns`) and `DISCOVERY_FILE`.
+    pub fn from_env() -> Self {
+        match std::env::var("DISCOVERY").as_deref() {
+            Ok("file") => Self::File(
+                std::env::var("DISCOVERY_FILE")
+                    .unwrap_or_else(|_| DEFAULT_FILE.to_string())
+                    .into(),
+            ),
+            Ok("dns") => Self::Dns,
+            Ok("env") | Err(_) => Self::Env,
+            Ok(other) => {
+                warn!("unknown discovery, using env: discovery={other}");
+                Self::Env
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    Io(std::io::Error),
+    Parse(String),
+    Resolve(String),
+}
+
+impl Display for DiscoveryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiscoveryError::Io(e) => write!(f, "error reading services: error={e}"),
+            DiscoveryError::Parse(e) => write!(f, "error parsing services: error={e}"),
+            DiscoveryError::Resolve(e) => write!(f, "error resolving service: error={e}"),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+impl From<std::io::Error> for DiscoveryError {
+    fn from(e: std::io::Error) -> Self {
+        DiscoveryError::Io(e)
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceFile {
+    #[serde(default)]
+    services: HashMap<String, Vec<String>>,
+}
+
+/// Resolves service names to the endpoints serving them.
+///
+/// Lookups are cached; `spawn_refresher` keeps file and DNS entries current,
+/// keeping the last known endpoints if a refresh fails. Endpoints marked
+/// unhealthy with `set_healthy` are left out until marked healthy again.
+pub struct ServiceRegistry {
+    discovery: Discovery,
+    refresh: Duration,
+    resolver: Option<TokioAsyncResolver>,
+    services: RwLock<HashMap<String, Vec<Endpoint>>>,
+    unhealthy: RwLock<HashSet<Endpoint>>,
+    file_modified: Mutex<Option<SystemTime>>,
+}
+
+impl ServiceRegistry {
+    pub fn new(discovery: Discovery, refresh: Duration) -> Self {
+        let resolver = match discovery {
+            Discovery::Dns => Some(TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
+                warn!("error reading resolv.conf, using defaults: error={e}");
+                TokioAsyncResolver::tokio(Default::default(), Default::default())
+            })),
+            _ => None,
+        };
+
+        Self {
+            discovery,
+            refresh,
+            resolver,
+            services: RwLock::new(HashMap::new()),
+            unhealthy: RwLock::new(HashSet::new()),
+            file_modified: Mutex::new(None),
+        }
+    }
+
+    /// See `Discovery::from_env`; also reads `DISCOVERY_REFRESH_MS`.
+    pub fn from_env() -> Self {
+        let discovery = Discovery::from_env();
+
+        let refresh = std::env::var("DISCOVERY_REFRESH_MS")
+            .ok()
+            .and_then(|refresh| refresh.parse().ok())
+            .unwrap_or(DEFAULT_REFRESH_MS);
+
+        info!("service registry: discovery={discovery:?}, refresh_ms={refresh}");
+
+        Self::new(discovery, Duration::from_millis(refresh))
+    }
+
+    /// Every endpoint for `service`, healthy or not, resolving it on first
+    /// use.
+    pub async fn all_endpoints(&self, service: &str) -> Vec<Endpoint> {
+        let cached = self.services.read().unwrap().get(service).cloned();
+
+        if let Some(endpoints) = cached {
+            return endpoints;
+        }
+
+        let endpoints = self.resolve(service).await.unwrap_or_else(|e| {
+            error!("error discovering service: service={service}, error={e}");
+            Vec::new()
+        });
+
+        info!("discovered service: service={service}, endpoints={endpoints:?}");
+
+        self.services
+            .write()
+            .unwrap()
+            .insert(service.to_string(), endpoints.clone());
+
+        endpoints
+    }
+
+    /// The healthy endpoints for `service`.
+    pub async fn endpoints(&self, service: &str) -> Vec<Endpoint> {
+        let endpoints = self.all_endpoints(service).await;
+
+        let unhealthy = self.unhealthy.read().unwrap();
+
+        endpoints
+            .into_iter()
+            .filter(|endpoint| !unhealthy.contains(endpoint))
+            .collect()
+    }
+
+    pub fn set_healthy(&self, endpoint: &Endpoint, healthy: bool) {
+        let mut unhealthy = self.unhealthy.write().unwrap();
+
+        let changed = match healthy {
+            true => unhealthy.remove(endpoint),
+            false => unhealthy.insert(endpoint.clone()),
+        };
+
+        if changed {
+            info!("endpoint health changed: endpoint={endpoint}, healthy={healthy}");
+        }
+    }
+
+    /// Re-reads the services file, or re-resolves every known service, every
+    /// `refresh`. Env endpoints never change, so there is nothing to do.
+    pub fn spawn_refresher(self: &Arc<Self>) {
+        if let Discovery::Env = self.discovery {
+            return;
+        }
+
+        let registry = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(registry.refresh).await;
+                registry.refresh_all().await;
+            }
+        });
+    }
+
+    async fn refresh_all(&self) {
+        let result = match &self.discovery {
+            Discovery::Env => Ok(()),
+            Discovery::File(path) => self.reload_file(path).await,
+            Discovery::Dns => {
+                let services: Vec<String> = self.services.read().unwrap().keys().cloned().collect();
+
+                for service in services {
+                    match self.resolve(&service).await {
+                        Ok(endpoints) => self.update(&service, endpoints),
+                        Err(e) => warn!("error refreshing service: service={service}, error={e}"),
+                    }
+                }
+
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("error refreshing services, keeping last known: error={e}");
+        }
+    }
+
+    /// Replaces every service's endpoints, if the file changed since it was
+    /// last read.
+    async fn reload_file(&self, path: &PathBuf) -> Result<(), DiscoveryError> {
+        let modified = tokio::fs::metadata(path).await?.modified()?;
+
+        if *self.file_modified.lock().unwrap() == Some(modified) {
+            return Ok(());
+        }
+
+        info!("services file changed, reloading: path={}", path.display());
+
+        let mut services = read_file(path).await?;
+
+        let known: Vec<String> = self.services.read().unwrap().keys().cloned().collect();
+
+        // Services dropped from the file have no endpoints left.
+        for service in known {
+            let endpoints = services.remove(&service).unwrap_or_default();
+            self.update(&service, endpoints);
+        }
+
+        for (service, endpoints) in services {
+            self.update(&service, endpoints);
+        }
+
+        *self.file_modified.lock().unwrap() = Some(modified);
+
+        Ok(())
+    }
+
+    fn update(&self, service: &str, endpoints: Vec<Endpoint>) {
+        let mut services = self.services.write().unwrap();
+
+        if services.get(service) != Some(&endpoints) {
+            info!("service endpoints changed: service={service}, endpoints={endpoints:?}");
+            services.insert(service.to_string(), endpoints);
+        }
+    }
+
+    async fn resolve(&self, service: &str) -> Result<Vec<Endpoint>, DiscoveryError> {
+        match &self.discovery {
+            Discovery::Env => Ok(env_endpoints(service)),
+            Discovery::File(path) => {
+                let mut services = read_file(path).await?;
+
+                Ok(services.remove(service).unwrap_or_else(|| {
+                    warn!("service missing from file: service={service}, path={}", path.display());
+                    Vec::new()
+                }))
+            }
+            Discovery::Dns => self.resolve_dns(service).await,
+        }
+    }
+
+    async fn resolve_dns(&self, service: &str) -> Result<Vec<Endpoint>, DiscoveryError> {
+        let resolver = self
+            .resolver
+            .as_ref()
+            .ok_or_else(|| DiscoveryError::Resolve("no resolver".to_string()))?;
+
+        if let Ok(srv) = std::env::var(format!("{service}_srv").to_uppercase()) {
+            let lookup = resolver
+                .srv_lookup(srv.as_str())
+                .await
+                .map_err(|e| DiscoveryError::Resolve(e.to_string()))?;
+
+            return Ok(lookup
+                .iter()
+                .map(|record| Endpoint {
+                    host: record.target().to_utf8().trim_end_matches('.').to_string(),
+                    port: record.port(),
+                })
+                .collect());
+        }
+
+        let mut endpoints = Vec::new();
+
+        for endpoint in env_endpoints(service) {
+            let lookup = resolver
+                .lookup_ip(endpoint.host.as_str())
+                .await
+                .map_err(|e| DiscoveryError::Resolve(e.to_string()))?;
+
+            endpoints.extend(lookup.iter().map(|ip| Endpoint {
+                host: ip.to_string(),
+                port: endpoint.port,
+            }));
+        }
+
+        Ok(endpoints)
+    }
+}
+
+async fn read_file(path: &PathBuf) -> Result<HashMap<String, Vec<Endpoint>>, DiscoveryError> {
+    let contents = tokio::fs::read_to_string(path).await?;
+
+    let file: ServiceFile = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str(&contents).map_err(|e| DiscoveryError::Parse(e.to_string()))?,
+        _ => toml::from_str(&contents).map_err(|e| DiscoveryError::Parse(e.to_string()))?,
+    };
+
+    Ok(file
+        .services
+        .into_iter()
+        .map(|(service, addresses)| {
+            let endpoints = addresses
+                .iter()
+                .filter_map(|address| {
+                    let endpoint = Endpoint::parse(address, DEFAULT_PORT);
+
+                    if endpoint.is_none() {
+                        warn!("skipping bad endpoint: service={service}, address={address}");
+                    }
+
+                    endpoint
+                })
+                .collect();
+
+            (service, endpoints)
+        })
+        .collect())
+}
+
+/// The endpoints in `{SERVICE}_ADDRESS` and `{SERVICE}_PORT`, defaulting to
+/// `{service}:8080`.
+pub fn env_endpoints(service: &str) -> Vec<Endpoint> {
+    let address = std::env::var(format!("{service}_address").to_uppercase()).unwrap_or_else(|_| {
+        warn!("using default address for service={service}");
+        service.to_string()
+    });
+
+    let port = match std::env::var(format!("{service}_port").to_uppercase()) {
+        Ok(port) => port.parse().unwrap_or_else(|_| {
+            warn!("bad port, using default: service={service}, port={port}");
+            DEFAULT_PORT
+        }),
+        Err(_) => {
+            warn!("using default port for service={service}");
+            DEFAULT_PORT
+        }
+    };
+
+    address
+        .split(',')
+        .filter_map(|address| Endpoint::parse(address, port))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(host: &str, port: u16) -> Option<Endpoint> {
+        Some(Endpoint {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    #[test]
+    fn parses_hosts_with_and_without_ports() {
+        assert_eq!(Endpoint::parse("entity", 8081), endpoint("entity", 8081));
+        assert_eq!(Endpoint::parse(" entity:9000 ", 8081), endpoint("entity", 9000));
+        assert_eq!(Endpoint::parse("entity:nope", 8081), None);
+        assert_eq!(Endpoint::parse(":9000", 8081), None);
+    }
+
+    #[test]
+    fn parses_ipv6_addresses() {
+        assert_eq!(Endpoint::parse("[::1]:9000", 8081), endpoint("::1", 9000));
+        assert_eq!(Endpoint::parse("[::1]", 8081), endpoint("::1", 8081));
+        assert_eq!(Endpoint::parse("fd00::2", 8081), endpoint("fd00::2", 8081));
+        assert_eq!(Endpoint::parse("[::1]9000", 8081), None);
+    }
+
+    #[test]
+    fn brackets_ipv6_hosts_in_urls() {
+        assert_eq!(endpoint("::1", 9000).unwrap().url(), "http://[::1]:9000");
+        assert_eq!(endpoint("entity", 9000).unwrap().url(), "http://entity:9000");
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::{header, HeaderMap, Method};
+use rand::Rng;
+use tracing::warn;
+
+use crate::context::remaining;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BASE_DELAY_MS: u64 = 50;
+const DEFAULT_MAX_DELAY_MS: u64 = 1000;
+const DEFAULT_BUDGET_RATIO: f64 = 0.1;
+const DEFAULT_BUDGET_MIN_PER_SEC: f64 = 10.0;
+
+/// Seconds' worth of `min_per_sec` the budget can bank.
+const BUDGET_WINDOW_SECS: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    /// Attempts per call, including the first.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Retries earned by each call, e.g. 0.1 allows one retry per ten calls.
+    pub budget_ratio: f64,
+    /// Retries allowed per second regardless of traffic.
+    pub budget_min_per_sec: f64,
+}
+
+impl RetryConfig {
+    /// Reads `RETRY_MAX_ATTEMPTS`, `RETRY_BASE_DELAY_MS`, `RETRY_MAX_DELAY_MS`,
+    /// `RETRY_BUDGET_RATIO` and `RETRY_BUDGET_MIN_PER_SEC`.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(key: &str, default: T) -> T {
+            std::env::var(key)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            max_attempts: var("RETRY_MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS).max(1),
+            base_delay: Duration::from_millis(var("RETRY_BASE_DELAY_MS", DEFAULT_BASE_DELAY_MS)),
+            max_delay: Duration::from_millis(var("RETRY_MAX_DELAY_MS", DEFAULT_MAX_DELAY_MS)),
+            budget_ratio: var("RETRY_BUDGET_RATIO", DEFAULT_BUDGET_RATIO).max(0.0),
+            budget_min_per_sec: var("RETRY_BUDGET_MIN_PER_SEC", DEFAULT_BUDGET_MIN_PER_SEC).max(0.0),
+        }
+    }
+}
+
+struct Budget {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Retries idempotent calls with exponential backoff and full jitter.
+///
+/// Retries are paid for out of a budget that each call tops up by
+/// `budget_ratio`, so when everything is failing the retries stop instead of
+/// multiplying the load on the upstream.
+pub struct RetryPolicy {
+    config: RetryConfig,
+    budget: Mutex<Budget>,
+}
+
+impl RetryPolicy {
+    pub fn new(config: RetryConfig) -> Self {
+        Self {
+            config,
+            budget: Mutex::new(Budget {
+                tokens: config.budget_min_per_sec,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(RetryConfig::from_env())
+    }
+
+    /// Makes the call built by `call`, making it again while `should_retry`
+    /// says so. Calls that aren't `idempotent` are made once.
+    pub async fn run<T, E, F, Fut, R>(&self, idempotent: bool, mut call: F, should_retry: R) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        R: Fn(&Result<T, E>) -> bool,
+    {
+        self.deposit();
+
+        let mut attempt = 1;
+
+        loop {
+            let result = call().await;
+
+            if !idempotent || attempt >= self.config.max_attempts || !should_retry(&result) {
+                return result;
+            }
+
+            if deadline_passed() {
+                warn!("deadline exceeded, not retrying: attempt={attempt}");
+                return result;
+            }
+
+            if !self.withdraw() {
+                warn!("retry budget exhausted, not retrying: attempt={attempt}");
+                return result;
+            }
+
+            let delay = self.backoff(attempt);
+
+            warn!("retrying: attempt={attempt}, delay_ms={}", delay.as_millis());
+
+            tokio::time::sleep(delay).await;
+
+            attempt += 1;
+        }
+    }
+
+    /// A random delay up to `base_delay * 2^(attempt - 1)`, capped at
+    /// `max_delay`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .config
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.config.max_delay);
+
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    fn deposit(&self) {
+        let mut budget = self.budget.lock().unwrap();
+
+        self.refill(&mut budget);
+        budget.tokens = (budget.tokens + self.config.budget_ratio).min(self.capacity());
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut budget = self.budget.lock().unwrap();
+
+        self.refill(&mut budget);
+
+        match budget.tokens >= 1.0 {
+            true => {
+                budget.tokens -= 1.0;
+                true
+            }
+            false => false,
+        }
+    }
+
+    fn refill(&self, budget: &mut Budget) {
+        let elapsed = budget.refilled_at.elapsed().as_secs_f64();
+
+        budget.tokens = (budget.tokens + elapsed * self.config.budget_min_per_sec).min(self.capacity());
+        budget.refilled_at = Instant::now();
+    }
+
+    fn capacity(&self) -> f64 {
+        (self.config.budget_min_per_sec * BUDGET_WINDOW_SECS).max(1.0)
+    }
+}
+
+/// Whether a request can safely be sent more than once: GET, HEAD, OPTIONS
+/// and DELETE always, PUT and PATCH only when guarded by `If-Match`.
+pub fn is_idempotent(method: &Method, headers: &HeaderMap) -> bool {
+    match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::DELETE => true,
+        Method::PUT | Method::PATCH => headers.contains_key(header::IF_MATCH),
+        _ => false,
+    }
+}
+
+/// Upstream statuses worth another attempt.
+pub fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 502..=504)
+}
+
+/// Failures to get a response worth another attempt: all but a timeout
+/// from the current request's deadline running out.
+pub fn is_retryable_error(e: &reqwest::Error) -> bool {
+    !(e.is_timeout() && deadline_passed())
+}
+
+/// Whether a call that ended in `result` is worth making again; what the
+/// proxy and `ResourceClient` both retry on.
+pub fn should_retry(result: &Result<reqwest::Response, reqwest::Error>) -> bool {
+    match result {
+        Ok(response) => is_retryable_status(response.status().as_u16()),
+        Err(e) => is_retryable_error(e),
+    }
+}
+
+fn deadline_passed() -> bool {
+    remaining().is_some_and(|remaining| remaining.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::context::scope_deadline;
+
+    fn policy(max_attempts: u32, budget_ratio: f64, budget_min_per_sec: f64) -> RetryPolicy {
+        RetryPolicy::new(RetryConfig {
+            max_attempts,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            budget_ratio,
+            budget_min_per_sec,
+        })
+    }
+
+    /// Runs a call that always fails, returning how many times it was made.
+    async fn failing(policy: &RetryPolicy, idempotent: bool) -> u32 {
+        let calls = AtomicU32::new(0);
+
+        let _ = policy
+            .run(
+                idempotent,
+                || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err::<(), ()>(())
+                },
+                |result| result.is_err(),
+            )
+            .await;
+
+        calls.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn retries_up_to_max_attempts() {
+        assert_eq!(failing(&policy(3, 0.0, 10.0), true).await, 3);
+    }
+
+    #[tokio::test]
+    async fn stops_once_the_call_succeeds() {
+        let calls = AtomicU32::new(0);
+
+        let result = policy(5, 0.0, 10.0)
+            .run(
+                true,
+                || async { if calls.fetch_add(1, Ordering::SeqCst) < 1 { Err(()) } else { Ok(()) } },
+                |result| result.is_err(),
+            )
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn sends_non_idempotent_calls_once() {
+        assert_eq!(failing(&policy(3, 0.0, 10.0), false).await, 1);
+    }
+
+    #[tokio::test]
+    async fn stops_once_the_deadline_has_passed() {
+        let deadline = tokio::time::Instant::now();
+
+        assert_eq!(scope_deadline(deadline, failing(&policy(3, 0.0, 10.0), true)).await, 1);
+    }
+
+    #[test]
+    fn retries_only_gateway_statuses() {
+        assert!(!is_retryable_status(500));
+        assert!((502..=504).all(is_retryable_status));
+        assert!(!is_retryable_status(429));
+    }
+
+    #[tokio::test]
+    async fn budget_limits_retries_to_its_ratio() {
+        // Nothing banked, and each call earns half a retry.
+        let policy = policy(3, 0.5, 0.0);
+
+        assert_eq!(failing(&policy, true).await, 1);
+        assert_eq!(failing(&policy, true).await, 2);
+        assert_eq!(failing(&policy, true).await, 1);
+        assert_eq!(failing(&policy, true).await, 2);
+    }
+
+    #[test]
+    fn backoff_stays_under_its_ceiling() {
+        let policy = RetryPolicy::new(RetryConfig {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(250),
+            budget_ratio: 0.1,
+            budget_min_per_sec: 10.0,
+        });
+
+        for _ in 0..100 {
+            assert!(policy.backoff(1) <= Duration::from_millis(100));
+            assert!(policy.backoff(4) <= Duration::from_millis(250));
+        }
+    }
+
+    #[test]
+    fn only_safe_methods_are_idempotent() {
+        let mut guarded = HeaderMap::new();
+        guarded.insert(header::IF_MATCH, "\"1\"".parse().unwrap());
+
+        assert!(is_idempotent(&Method::GET, &HeaderMap::new()));
+        assert!(is_idempotent(&Method::DELETE, &HeaderMap::new()));
+        assert!(!is_idempotent(&Method::POST, &guarded));
+        assert!(!is_idempotent(&Method::PUT, &HeaderMap::new()));
+        assert!(is_idempotent(&Method::PUT, &guarded));
+    }
+}
@@ -0,0 +1,25 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// A single mutation of an `AppState`, as seen by watchers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeEvent<T> {
+    pub key: String,
+    pub version: u64,
+    pub old: Option<T>,
+    pub new: Option<T>,
+    pub logid: Option<String>,
+}
+
+impl<T> Display for ChangeEvent<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "key={}, version={}, logid={}",
+            self.key,
+            self.version,
+            self.logid.as_deref().unwrap_or("None")
+        )
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt::{Display, Formatter};
 
-use super::{entity::Entity, property::Property, Partial};
+use super::{entity::Entity, property::Property, query::Filterable, Partial};
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct Combo {
@@ -20,6 +20,20 @@ impl Display for Combo {
     }
 }
 
+impl Filterable for Combo {
+    const FIELDS: &'static [&'static str] = &["origin", "colour", "property", "value"];
+
+    fn field(&self, name: &str) -> Option<&str> {
+        match name {
+            "origin" => Some(&self.origin),
+            "colour" => Some(&self.colour),
+            "property" => Some(&self.property),
+            "value" => Some(&self.value),
+            _ => None,
+        }
+    }
+}
+
 impl Combo {
     pub fn new(origin: &str, colour: &str, property: &str, value: &str) -> Self {
         Combo {
@@ -47,15 +61,15 @@ impl Combo {
     }
 }
 
-impl Into<Entity> for Combo {
-    fn into(self) -> Entity {
-        Entity::new(self.origin(), self.colour())
+impl From<Combo> for Entity {
+    fn from(combo: Combo) -> Self {
+        Entity::new(combo.origin(), combo.colour())
     }
 }
 
-impl Into<Property> for Combo {
-    fn into(self) -> Property {
-        Property::new(self.property(), self.value())
+impl From<Combo> for Property {
+    fn from(combo: Combo) -> Self {
+        Property::new(combo.property(), combo.value())
     }
 }
 
@@ -89,6 +103,34 @@ impl From<Entity> for MaybeCombo {
     }
 }
 
+impl From<(Entity, Option<Property>)> for MaybeCombo {
+    fn from((entity, property): (Entity, Option<Property>)) -> Self {
+        MaybeCombo {
+            origin: entity.origin().to_string(),
+            colour: entity.colour().to_string(),
+            property: property.as_ref().map(|property| property.property().to_string()),
+            value: property.as_ref().map(|property| property.value().to_string()),
+        }
+    }
+}
+
+impl From<Combo> for MaybeCombo {
+    fn from(combo: Combo) -> Self {
+        MaybeCombo {
+            origin: combo.origin,
+            colour: combo.colour,
+            property: Some(combo.property),
+            value: Some(combo.value),
+        }
+    }
+}
+
+impl From<MaybeCombo> for Entity {
+    fn from(combo: MaybeCombo) -> Self {
+        Entity::new(&combo.origin, &combo.colour)
+    }
+}
+
 impl TryInto<Property> for MaybeCombo {
     type Error = String;
 
@@ -1,9 +1,9 @@
 use std::fmt::{Formatter, Display};
 
-use super::Partial;
+use super::{query::Filterable, Partial};
 
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct Entity {
     origin: String,
     colour: String,
@@ -15,6 +15,18 @@ impl Display for Entity {
     }
 }
 
+impl Filterable for Entity {
+    const FIELDS: &'static [&'static str] = &["origin", "colour"];
+
+    fn field(&self, name: &str) -> Option<&str> {
+        match name {
+            "origin" => Some(&self.origin),
+            "colour" => Some(&self.colour),
+            _ => None,
+        }
+    }
+}
+
 impl Entity {
     pub fn new(origin: &str, colour: &str) -> Self {
         Entity {
@@ -0,0 +1,58 @@
+use std::collections::{BTreeSet, HashMap};
+
+type Extractor<T> = Box<dyn Fn(&T) -> String + Send + Sync>;
+
+/// Secondary index from an extracted field value to the keys holding it.
+///
+/// Indexes live under the same lock as the values they cover and are updated
+/// on every write, so they never disagree with the table.
+pub struct Index<T> {
+    field: String,
+    extractor: Extractor<T>,
+    entries: HashMap<String, BTreeSet<String>>,
+}
+
+impl<T> Index<T> {
+    pub fn new<F>(field: &str, extractor: F) -> Self
+    where
+        F: Fn(&T) -> String + Send + Sync + 'static,
+    {
+        Self {
+            field: field.to_string(),
+            extractor: Box::new(extractor),
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    pub fn insert(&mut self, key: &str, value: &T) {
+        self.entries
+            .entry((self.extractor)(value))
+            .or_default()
+            .insert(key.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str, value: &T) {
+        let indexed = (self.extractor)(value);
+
+        if let Some(keys) = self.entries.get_mut(&indexed) {
+            keys.remove(key);
+
+            if keys.is_empty() {
+                self.entries.remove(&indexed);
+            }
+        }
+    }
+
+    /// Keys whose extracted value equals `value`, in key order.
+    pub fn keys(&self, value: &str) -> Option<&BTreeSet<String>> {
+        self.entries.get(value)
+    }
+
+    pub fn matches(&self, value: &str) -> usize {
+        self.keys(value).map_or(0, BTreeSet::len)
+    }
+}
@@ -1,71 +1,534 @@
-use std::{collections::HashMap, sync::Arc, fmt::Display};
+use std::{collections::VecDeque, ops::Bound, sync::{Arc, Mutex as SyncMutex}, fmt::{Display, Formatter}};
 
-use tokio::sync::Mutex;
-use tracing::{info, warn};
+use axum::http::StatusCode;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{broadcast, Mutex};
+use tracing::{error, info, warn};
+
+use crate::context::current_logid;
+
+use self::{
+    change::ChangeEvent,
+    index::Index,
+    query::{BatchResult, Filterable, Item, ListQuery, Order, Page},
+    storage::{MemoryStorage, Record, Storage, StorageError, Table},
+    version::{Precondition, Versioned},
+};
 
 pub mod property;
 pub mod entity;
 pub mod combo;
+pub mod change;
+pub mod index;
+pub mod query;
+pub mod storage;
+pub mod version;
+
+const DEFAULT_CHANGE_HISTORY: usize = 1024;
+
+type SharedStorage<T> = Arc<SyncMutex<Box<dyn Storage<T>>>>;
+
+struct Inner<T> {
+    table: Table<T>,
+    /// Only used from blocking tasks, so file io and fsyncs don't hold up
+    /// the runtime while the state is locked.
+    storage: SharedStorage<T>,
+    indexes: Vec<Index<T>>,
+    changes: broadcast::Sender<ChangeEvent<T>>,
+    history: VecDeque<ChangeEvent<T>>,
+    history_capacity: usize,
+}
+
+impl <T> Inner<T>
+where T: Clone + Send + 'static
+{
+    fn new(table: Table<T>, storage: Box<dyn Storage<T>>) -> Self {
+        let history_capacity = std::env::var("CHANGE_HISTORY")
+            .ok()
+            .and_then(|capacity| capacity.parse().ok())
+            .unwrap_or(DEFAULT_CHANGE_HISTORY);
+
+        Self {
+            table,
+            storage: Arc::new(SyncMutex::new(storage)),
+            indexes: Vec::new(),
+            changes: broadcast::channel(history_capacity.max(1)).0,
+            history: VecDeque::with_capacity(history_capacity),
+            history_capacity,
+        }
+    }
+
+    fn current_version(&self, key: &str) -> Option<u64> {
+        self.table.values.get(key).map(|value| value.version)
+    }
+
+    async fn apply(&mut self, record: Record<T>, logid: Option<String>) -> Result<(), StateError> {
+        // The state stays locked while the record is written, so records
+        // reach storage in version order.
+        let record = blocking(&self.storage, move |storage| {
+            storage.append(&record)?;
+            Ok(record)
+        })
+        .await?;
+
+        let key = record.key().to_string();
+        let version = record.version();
+
+        let previous = self.table.values.get(&key).map(|value| value.value.clone());
+
+        if let Some(previous) = &previous {
+            self.indexes.iter_mut().for_each(|index| index.remove(&key, previous));
+        }
+
+        record.apply(&mut self.table);
+
+        let current = self.table.values.get(&key).map(|value| value.value.clone());
+
+        if let Some(current) = &current {
+            self.indexes.iter_mut().for_each(|index| index.insert(&key, current));
+        }
+
+        self.publish(ChangeEvent {
+            key,
+            version,
+            old: previous,
+            new: current,
+            logid,
+        });
+
+        if self.storage.lock().unwrap().wants_snapshot() {
+            let table = self.table.clone();
+
+            // The log still holds every record, so a failed snapshot loses nothing.
+            if let Err(e) = blocking(&self.storage, move |storage| storage.snapshot(&table)).await {
+                error!("failed to write snapshot: error={e}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A record storing `value` at the next version.
+    fn set_record(&self, key: String, value: T) -> (Record<T>, Versioned<T>) {
+        let version = self.table.version + 1;
+
+        (Record::Set { key, version, value: value.clone() }, Versioned::new(version, value))
+    }
+
+    fn remove_record(&self, key: String) -> Record<T> {
+        Record::Remove { key, version: self.table.version + 1 }
+    }
+
+    fn publish(&mut self, event: ChangeEvent<T>) {
+        if self.history_capacity > 0 {
+            if self.history.len() == self.history_capacity {
+                self.history.pop_front();
+            }
+
+            self.history.push_back(event.clone());
+        }
+
+        // Sending only fails when nobody is watching.
+        let _ = self.changes.send(event);
+    }
+
+    /// The index covering one of `query`'s filters with the fewest matches.
+    fn plan<'a>(&'a self, query: &'a ListQuery) -> Option<(&'a Index<T>, &'a str)> {
+        query
+            .filters()
+            .iter()
+            .filter_map(|(field, value)| {
+                self.indexes
+                    .iter()
+                    .find(|index| index.field() == field)
+                    .map(|index| (index, value.as_str()))
+            })
+            .min_by_key(|(index, value)| index.matches(value))
+    }
+}
+
+/// Runs `f` against the storage on the blocking pool.
+async fn blocking<T, R, F>(storage: &SharedStorage<T>, f: F) -> Result<R, StorageError>
+where
+    T: 'static,
+    R: Send + 'static,
+    F: FnOnce(&mut dyn Storage<T>) -> Result<R, StorageError> + Send + 'static,
+{
+    let storage = storage.clone();
+
+    tokio::task::spawn_blocking(move || f(storage.lock().unwrap().as_mut()))
+        .await
+        .map_err(|e| StorageError::Io(std::io::Error::other(e)))?
+}
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct AppState<T> {
-    state: Arc::<Mutex<HashMap<String, T>>>,
+    state: Arc::<Mutex<Inner<T>>>,
 }
 
-impl <T> Default for AppState<T> 
-where T: Clone + Display
+impl <T> Default for AppState<T>
+where T: Clone + Display + Send + 'static
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl <T> AppState<T> 
-where T: Clone + Display
+impl <T> AppState<T>
+where T: Clone + Display + Send + 'static
 {
     pub fn new() -> Self {
         Self {
-            state: Arc::new(Mutex::new(HashMap::new())),
+            state: Arc::new(Mutex::new(Inner::new(Table::default(), Box::new(MemoryStorage)))),
         }
     }
 
-    pub async fn get(&self, key: &str) -> Option<T> {
+    pub fn with_storage(mut storage: Box<dyn Storage<T>>) -> Result<Self, StorageError> {
+        let table = storage.load()?;
+
+        info!("Loaded state: items={}, version={}", table.values.len(), table.version);
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(Inner::new(table, storage))),
+        })
+    }
+
+    /// Declares a secondary index over `field`, used by list queries that
+    /// filter on it.
+    ///
+    /// Panics if the state has already been cloned, as the clones would not
+    /// see the index.
+    pub fn with_index<F>(mut self, field: &str, extractor: F) -> Self
+    where F: Fn(&T) -> String + Send + Sync + 'static
+    {
+        let state = Arc::get_mut(&mut self.state)
+            .expect("indexes must be declared before the state is shared")
+            .get_mut();
+
+        let mut index = Index::new(field, extractor);
+
+        for (key, value) in &state.table.values {
+            index.insert(key, &value.value);
+        }
+
+        info!("Built index: field={field}");
+
+        state.indexes.push(index);
+
+        self
+    }
+
+    pub fn from_env() -> Result<Self, StorageError>
+    where T: Serialize + DeserializeOwned + Send
+    {
+        Self::with_storage(storage::from_env()?)
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Versioned<T>> {
         let state = self.state.lock().await;
-        state.get(key).cloned()
+        state.table.values.get(key).cloned()
     }
 
-    pub async fn set(&self, key: &str, value: &T) -> Option<T> {
-        let mut state = self.state.lock().await;
-        state.insert(key.to_string(), value.clone())
+    /// The items named in `names`, read under one lock.
+    pub async fn get_many(&self, names: &[String]) -> BatchResult<T> {
+        let state = self.state.lock().await;
+
+        let mut items = Vec::with_capacity(names.len());
+        let mut missing = Vec::new();
+
+        for name in names {
+            match state.table.values.get(name) {
+                Some(value) => items.push(Item {
+                    name: name.clone(),
+                    version: Some(value.version),
+                    value: value.value.clone(),
+                }),
+                None => missing.push(name.clone()),
+            }
+        }
+
+        BatchResult { items, missing }
     }
 
-    pub async fn update<U: Partial<T> + Clone>(&self, key: &str, partial_value: &U) -> Option<T> {
-        let mut state = self.state.lock().await;
+    /// Every item, in key order.
+    pub async fn entries(&self) -> Vec<Item<T>> {
+        let state = self.state.lock().await;
+
+        state.table.values
+            .iter()
+            .map(|(key, value)| Item {
+                name: key.clone(),
+                version: Some(value.version),
+                value: value.value.clone(),
+            })
+            .collect()
+    }
 
-        match self.get(key).await {
-            Some(value) => {
-                info!("Patch item: {key}:{value}");
-                state.insert(key.to_string(), partial_value.clone().merge(&value))
+    /// The version of the latest change.
+    pub async fn version(&self) -> u64 {
+        self.state.lock().await.table.version
+    }
+
+    /// Subscribes to changes made after version `since`, returning the
+    /// retained changes already past it alongside the live feed.
+    ///
+    /// Fails if changes after `since` are no longer retained, in which case
+    /// the watcher has to re-list and resume from a fresh version, or if
+    /// `since` is a version not handed out yet.
+    pub async fn watch(&self, since: Option<u64>) -> Result<(Vec<ChangeEvent<T>>, broadcast::Receiver<ChangeEvent<T>>), StateError> {
+        let state = self.state.lock().await;
+
+        let since = since.unwrap_or(state.table.version);
+
+        if since > state.table.version {
+            return Err(StateError::InvalidQuery(format!(
+                "cursor ahead of current version: since={since}, version={}",
+                state.table.version
+            )));
+        }
+
+        let oldest = state.history.front().map_or(state.table.version.saturating_add(1), |event| event.version);
+
+        if since.saturating_add(1) < oldest {
+            return Err(StateError::HistoryExpired { oldest });
+        }
+
+        let backlog = state.history
+            .iter()
+            .filter(|event| event.version > since)
+            .cloned()
+            .collect();
+
+        Ok((backlog, state.changes.subscribe()))
+    }
+
+    /// Returns the page of items selected by `query`, in key order.
+    pub async fn list(&self, query: &ListQuery) -> Page<T>
+    where T: Filterable
+    {
+        let state = self.state.lock().await;
+
+        let start = match (query.order(), query.cursor(), query.prefix()) {
+            (Order::Asc, Some(cursor), Some(prefix)) if prefix > cursor => Bound::Included(prefix),
+            (Order::Asc, Some(cursor), _) => Bound::Excluded(cursor),
+            (Order::Asc, None, Some(prefix)) => Bound::Included(prefix),
+            _ => Bound::Unbounded,
+        };
+
+        let end = match (query.order(), query.cursor()) {
+            (Order::Desc, Some(cursor)) => Bound::Excluded(cursor),
+            _ => Bound::Unbounded,
+        };
+
+        let range: Box<dyn DoubleEndedIterator<Item = (&String, &Versioned<T>)>> = match state.plan(query) {
+            Some((index, value)) => match index.keys(value) {
+                Some(keys) => Box::new(
+                    keys.range::<str, _>((start, end))
+                        .filter_map(|key| state.table.values.get_key_value(key)),
+                ),
+                None => Box::new(std::iter::empty()),
             },
-            None => {
-                warn!("Attempted to patch item: {key} but didn't exist");
-                None
+            None => Box::new(state.table.values.range::<str, _>((start, end))),
+        };
+
+        let keys: Box<dyn Iterator<Item = (&String, &Versioned<T>)>> = match query.order() {
+            Order::Asc => range,
+            Order::Desc => Box::new(range.rev().skip_while(|(key, _)| {
+                // Skip past keys sorting after every key with the prefix.
+                query.prefix().is_some_and(|prefix| !key.starts_with(prefix) && key.as_str() > prefix)
+            })),
+        };
+
+        let items = keys
+            .take_while(|(key, _)| query.matches_key(key))
+            .filter(|(_, value)| query.matches_value(&value.value))
+            .take(query.limit() + 1)
+            .map(|(key, value)| Item {
+                name: key.clone(),
+                version: Some(value.version),
+                value: value.value.clone(),
+            })
+            .collect();
+
+        Page::from_matches(items, query.limit())
+    }
+
+    /// Decides on a change with the state locked, then writes it to storage
+    /// and applies it in memory on a task of its own, so a caller cancelled
+    /// part way can't leave storage holding a write memory never saw.
+    async fn write<R, F>(&self, decide: F) -> Result<R, StateError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Inner<T>) -> Result<(Option<Record<T>>, R), StateError> + Send + 'static,
+    {
+        let mut state = self.state.clone().lock_owned().await;
+        let logid = current_logid();
+
+        tokio::spawn(async move {
+            let (record, result) = decide(&state)?;
+
+            if let Some(record) = record {
+                state.apply(record, logid).await?;
             }
-        }
+
+            Ok(result)
+        })
+        .await
+        .map_err(|e| StateError::Storage(StorageError::Io(std::io::Error::other(e))))?
+    }
+
+    pub async fn set(&self, key: &str, value: &T, precondition: &Precondition) -> Result<Versioned<T>, StateError> {
+        let (key, value, precondition) = (key.to_string(), value.clone(), precondition.clone());
+
+        self.write(move |state| {
+            precondition.evaluate(state.current_version(&key))?;
+
+            let (record, stored) = state.set_record(key, value);
+
+            Ok((Some(record), stored))
+        })
+        .await
     }
 
-    pub async fn rm(&self, key: &str) -> Option<T> {
-        let mut state = self.state.lock().await;
-        
-        match state.remove(key) {
+    pub async fn update<U: Partial<T> + Clone + Send + 'static>(&self, key: &str, partial_value: &U) -> Result<Option<Versioned<T>>, StateError> {
+        let (name, partial_value) = (key.to_string(), partial_value.clone());
+
+        self.update_with(key, move |current| match current {
             Some(value) => {
-                info!("Removed item: {key}:{value}");
-                Some(value)
+                info!("Patch item: {name}:{value}");
+                Some(partial_value.merge(value))
             },
             None => {
-                warn!("Attempted to remove item: {key} but didn't exist");
+                warn!("Attempted to patch item: {name} but didn't exist");
                 None
             }
+        }).await
+    }
+
+    /// Atomically replaces the value for `key` with the result of `f`.
+    ///
+    /// `f` sees the current value, if any, while the state is locked. Returning
+    /// `None` removes the key. Returns the newly stored value.
+    pub async fn update_with<F>(&self, key: &str, f: F) -> Result<Option<Versioned<T>>, StateError>
+    where F: FnOnce(Option<&T>) -> Option<T> + Send + 'static
+    {
+        let key = key.to_string();
+
+        self.write(move |state| {
+            let current = state.table.values.get(&key).map(|value| &value.value);
+            let existed = current.is_some();
+
+            Ok(match f(current) {
+                Some(value) => {
+                    let (record, stored) = state.set_record(key, value);
+                    (Some(record), Some(stored))
+                }
+                None if existed => (Some(state.remove_record(key)), None),
+                None => (None, None),
+            })
+        })
+        .await
+    }
+
+    /// Stores `value` only if the key is still at `expected_version`, where
+    /// `None` expects the key to be absent.
+    pub async fn compare_and_swap(&self, key: &str, expected_version: Option<u64>, value: &T) -> Result<Versioned<T>, StateError> {
+        let (key, value) = (key.to_string(), value.clone());
+
+        self.write(move |state| {
+            let current = state.current_version(&key);
+
+            if current != expected_version {
+                return Err(StateError::PreconditionFailed { current });
+            }
+
+            let (record, stored) = state.set_record(key, value);
+
+            Ok((Some(record), stored))
+        })
+        .await
+    }
+
+    pub async fn insert_if_absent(&self, key: &str, value: &T) -> Result<Versioned<T>, StateError> {
+        self.compare_and_swap(key, None, value).await
+    }
+
+    pub async fn rm(&self, key: &str, precondition: &Precondition) -> Result<Option<Versioned<T>>, StateError> {
+        let name = key.to_string();
+        let precondition = precondition.clone();
+
+        let removed = self.write(move |state| {
+            precondition.evaluate(state.current_version(&name))?;
+
+            Ok(match state.table.values.get(&name).cloned() {
+                Some(value) => (Some(state.remove_record(name)), Some(value)),
+                None => (None, None),
+            })
+        })
+        .await?;
+
+        match &removed {
+            Some(value) => info!("Removed item: {key}:{value}"),
+            None => warn!("Attempted to remove item: {key} but didn't exist"),
+        }
+
+        Ok(removed)
+    }
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    Storage(StorageError),
+    PreconditionFailed { current: Option<u64> },
+    InvalidQuery(String),
+    HistoryExpired { oldest: u64 },
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::Storage(e) => write!(f, "{e}"),
+            StateError::PreconditionFailed { current: Some(version) } => {
+                write!(f, "precondition failed: current_version={version}")
+            }
+            StateError::PreconditionFailed { current: None } => {
+                write!(f, "precondition failed: item doesn't exist")
+            }
+            StateError::InvalidQuery(e) => write!(f, "invalid query: {e}"),
+            StateError::HistoryExpired { oldest } => {
+                write!(f, "change history expired: oldest_version={oldest}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<StorageError> for StateError {
+    fn from(e: StorageError) -> Self {
+        StateError::Storage(e)
+    }
+}
+
+impl From<StateError> for StatusCode {
+    fn from(e: StateError) -> Self {
+        match e {
+            StateError::Storage(e) => {
+                error!("state error: error={e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            StateError::PreconditionFailed { .. } => {
+                warn!("state error: error={e}");
+                StatusCode::PRECONDITION_FAILED
+            }
+            StateError::InvalidQuery(_) => {
+                warn!("state error: error={e}");
+                StatusCode::BAD_REQUEST
+            }
+            StateError::HistoryExpired { .. } => {
+                warn!("state error: error={e}");
+                StatusCode::GONE
+            }
         }
     }
 }
@@ -73,3 +536,111 @@ where T: Clone + Display
 pub trait Partial<T> {
     fn merge(self, property: &T) -> T;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::state::entity::Entity;
+
+    async fn names(state: &AppState<Entity>, params: &[(&str, &str)]) -> (Vec<String>, Option<String>) {
+        let params: HashMap<String, String> = params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        let page = state.list(&ListQuery::from_params::<Entity>(params).unwrap()).await;
+
+        (page.items.into_iter().map(|item| item.name).collect(), page.next_cursor)
+    }
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    async fn populated() -> AppState<Entity> {
+        let state = AppState::new();
+
+        for (name, colour) in [("a", "red"), ("ba", "red"), ("bb", "blue"), ("bc", "red"), ("c", "red")] {
+            state.set(name, &Entity::new("x", colour), &Precondition::none()).await.unwrap();
+        }
+
+        state
+    }
+
+    #[tokio::test]
+    async fn list_pages_through_a_prefix_with_cursors() {
+        let state = populated().await;
+
+        assert_eq!(names(&state, &[("prefix", "b"), ("limit", "2")]).await, (strings(&["ba", "bb"]), Some("bb".to_string())));
+        assert_eq!(names(&state, &[("prefix", "b"), ("limit", "2"), ("cursor", "bb")]).await, (strings(&["bc"]), None));
+
+        // A cursor before the prefix starts at the prefix.
+        assert_eq!(names(&state, &[("prefix", "b"), ("cursor", "a")]).await.0, strings(&["ba", "bb", "bc"]));
+    }
+
+    #[tokio::test]
+    async fn list_pages_backwards_in_descending_order() {
+        let state = populated().await;
+
+        assert_eq!(names(&state, &[("order", "desc"), ("limit", "2")]).await, (strings(&["c", "bc"]), Some("bc".to_string())));
+        assert_eq!(names(&state, &[("order", "desc"), ("limit", "2"), ("cursor", "bc")]).await, (strings(&["bb", "ba"]), Some("ba".to_string())));
+        assert_eq!(names(&state, &[("order", "desc"), ("prefix", "b")]).await.0, strings(&["bc", "bb", "ba"]));
+    }
+
+    #[tokio::test]
+    async fn list_filters_before_counting_the_page() {
+        let state = populated().await;
+
+        assert_eq!(names(&state, &[("colour", "red"), ("limit", "3")]).await, (strings(&["a", "ba", "bc"]), Some("bc".to_string())));
+        assert_eq!(names(&state, &[("colour", "red"), ("cursor", "bc")]).await, (strings(&["c"]), None));
+    }
+
+    /// Takes a while over every append, like a slow disk.
+    struct SlowStorage;
+
+    impl Storage<String> for SlowStorage {
+        fn load(&mut self) -> Result<Table<String>, StorageError> {
+            Ok(Table::default())
+        }
+
+        fn append(&mut self, _: &Record<String>) -> Result<(), StorageError> {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            Ok(())
+        }
+
+        fn wants_snapshot(&self) -> bool {
+            false
+        }
+
+        fn snapshot(&mut self, _: &Table<String>) -> Result<(), StorageError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_write_is_still_applied_once_stored() {
+        let state = AppState::with_storage(Box::new(SlowStorage)).unwrap();
+        let (_, mut changes) = state.watch(None).await.unwrap();
+
+        let (value, precondition) = ("1".to_string(), Precondition::none());
+        let write = state.set("a", &value, &precondition);
+
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(10), write).await.is_err());
+
+        // Reads wait behind the write, which carries on without its caller.
+        assert_eq!(state.get("a").await.map(|value| value.version), Some(1));
+        assert_eq!(changes.recv().await.unwrap().version, 1);
+    }
+
+    #[tokio::test]
+    async fn watch_rejects_cursors_ahead_of_the_state() {
+        let state = AppState::<String>::new();
+
+        state.set("a", &"1".to_string(), &Precondition::none()).await.unwrap();
+
+        assert!(matches!(state.watch(Some(u64::MAX)).await, Err(StateError::InvalidQuery(_))));
+        assert!(matches!(state.watch(Some(2)).await, Err(StateError::InvalidQuery(_))));
+
+        let (backlog, _) = state.watch(Some(0)).await.unwrap();
+
+        assert_eq!(backlog.len(), 1);
+    }
+}
@@ -1,10 +1,10 @@
 use std::fmt::{Formatter, Display};
 use serde::{Serialize, Deserialize};
 
-use super::Partial;
+use super::{query::Filterable, Partial};
 
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Property {
     property: String,
     value: String,
@@ -22,6 +22,18 @@ impl Default for Property {
     }
 }
 
+impl Filterable for Property {
+    const FIELDS: &'static [&'static str] = &["property", "value"];
+
+    fn field(&self, name: &str) -> Option<&str> {
+        match name {
+            "property" => Some(&self.property),
+            "value" => Some(&self.value),
+            _ => None,
+        }
+    }
+}
+
 impl Property {
     pub fn new(property: &str, value: &str) -> Self {
         Property {
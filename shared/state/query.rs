@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::StateError;
+
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+const PREFIX_PARAM: &str = "prefix";
+const CURSOR_PARAM: &str = "cursor";
+const LIMIT_PARAM: &str = "limit";
+const ORDER_PARAM: &str = "order";
+
+/// Named string fields that list queries can filter on, e.g. `colour=red`.
+pub trait Filterable {
+    const FIELDS: &'static [&'static str];
+
+    fn field(&self, name: &str) -> Option<&str>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// A page request over the keys of an `AppState`, in key order.
+///
+/// The cursor is the last key of the previous page and is exclusive.
+#[derive(Debug, Clone, Default)]
+pub struct ListQuery {
+    prefix: Option<String>,
+    cursor: Option<String>,
+    limit: usize,
+    order: Order,
+    filters: Vec<(String, String)>,
+}
+
+impl ListQuery {
+    /// Parses query parameters, treating every unreserved parameter as a
+    /// filter on a field of `T`.
+    pub fn from_params<T: Filterable>(params: HashMap<String, String>) -> Result<Self, StateError> {
+        let mut query = ListQuery {
+            limit: DEFAULT_LIMIT,
+            ..Default::default()
+        };
+
+        for (key, value) in params {
+            match key.as_str() {
+                PREFIX_PARAM => query.prefix = Some(value),
+                CURSOR_PARAM => query.cursor = Some(value),
+                LIMIT_PARAM => {
+                    query.limit = match value.parse() {
+                        Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => limit,
+                        _ => return Err(StateError::InvalidQuery(format!("limit must be between 1 and {MAX_LIMIT}"))),
+                    }
+                }
+                ORDER_PARAM => {
+                    query.order = match value.as_str() {
+                        "asc" => Order::Asc,
+                        "desc" => Order::Desc,
+                        _ => return Err(StateError::InvalidQuery(format!("unknown order={value}"))),
+                    }
+                }
+                field if T::FIELDS.contains(&field) => query.filters.push((key, value)),
+                _ => return Err(StateError::InvalidQuery(format!("unknown field={key}"))),
+            }
+        }
+
+        query.filters.sort();
+
+        Ok(query)
+    }
+
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    pub fn cursor(&self) -> Option<&str> {
+        self.cursor.as_deref()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn order(&self) -> Order {
+        self.order
+    }
+
+    pub fn filters(&self) -> &[(String, String)] {
+        &self.filters
+    }
+
+    /// A copy of this query keeping only the filters on `fields`.
+    pub fn retain_filters(&self, fields: &[&str]) -> Self {
+        Self {
+            filters: self
+                .filters
+                .iter()
+                .filter(|(field, _)| fields.contains(&field.as_str()))
+                .cloned()
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    pub fn matches_key(&self, key: &str) -> bool {
+        match &self.prefix {
+            Some(prefix) => key.starts_with(prefix.as_str()),
+            None => true,
+        }
+    }
+
+    pub fn matches_value<T: Filterable>(&self, value: &T) -> bool {
+        self.filters
+            .iter()
+            .all(|(field, expected)| value.field(field) == Some(expected.as_str()))
+    }
+
+    pub fn to_params(&self) -> Vec<(String, String)> {
+        let mut params = vec![(LIMIT_PARAM.to_string(), self.limit.to_string())];
+
+        if let Some(prefix) = &self.prefix {
+            params.push((PREFIX_PARAM.to_string(), prefix.clone()));
+        }
+
+        if let Some(cursor) = &self.cursor {
+            params.push((CURSOR_PARAM.to_string(), cursor.clone()));
+        }
+
+        if self.order == Order::Desc {
+            params.push((ORDER_PARAM.to_string(), "desc".to_string()));
+        }
+
+        params.extend(self.filters.iter().cloned());
+
+        params
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Item<T> {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    pub value: T,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<Item<T>>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Builds a page from up to `limit + 1` matches, the extra one only
+    /// signalling that another page exists.
+    pub fn from_matches(mut items: Vec<Item<T>>, limit: usize) -> Self {
+        let next_cursor = match items.len() > limit {
+            true => {
+                items.truncate(limit);
+                items.last().map(|item| item.name.clone())
+            }
+            false => None,
+        };
+
+        Self { items, next_cursor }
+    }
+}
+
+/// Body of a `:batchGet` request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchGet {
+    pub names: Vec<String>,
+}
+
+impl BatchGet {
+    /// Batches are capped at `MAX_LIMIT` names, like list pages.
+    pub fn validate(&self) -> Result<(), StateError> {
+        match self.names.len() {
+            0..=MAX_LIMIT => Ok(()),
+            _ => Err(StateError::InvalidQuery(format!("at most {MAX_LIMIT} names per batch"))),
+        }
+    }
+}
+
+/// The items found for a `BatchGet`, in request order, and the names that
+/// weren't.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResult<T> {
+    pub items: Vec<Item<T>>,
+    pub missing: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::entity::Entity;
+
+    fn query(params: &[(&str, &str)]) -> Result<ListQuery, StateError> {
+        ListQuery::from_params::<Entity>(params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect())
+    }
+
+    fn item(name: &str) -> Item<()> {
+        Item {
+            name: name.to_string(),
+            version: None,
+            value: (),
+        }
+    }
+
+    #[test]
+    fn parses_paging_and_filters() {
+        let query = query(&[("prefix", "a"), ("cursor", "ab"), ("limit", "2"), ("order", "desc"), ("origin", "x"), ("colour", "red")]).unwrap();
+
+        assert_eq!(query.prefix(), Some("a"));
+        assert_eq!(query.cursor(), Some("ab"));
+        assert_eq!(query.limit(), 2);
+        assert_eq!(query.order(), Order::Desc);
+        assert_eq!(query.filters(), [("colour".to_string(), "red".to_string()), ("origin".to_string(), "x".to_string())]);
+
+        let parsed = ListQuery::from_params::<Entity>(query.to_params().into_iter().collect()).unwrap();
+
+        assert_eq!(parsed.to_params(), query.to_params());
+    }
+
+    #[test]
+    fn defaults_to_the_first_ascending_page() {
+        let query = query(&[]).unwrap();
+
+        assert_eq!(query.limit(), DEFAULT_LIMIT);
+        assert_eq!(query.order(), Order::Asc);
+        assert_eq!(query.cursor(), None);
+    }
+
+    #[test]
+    fn rejects_bad_limits_orders_and_fields() {
+        for params in [[("limit", "0")], [("limit", "1001")], [("limit", "x")], [("order", "up")], [("size", "1")]] {
+            assert!(matches!(query(&params), Err(StateError::InvalidQuery(_))), "params={params:?}");
+        }
+    }
+
+    #[test]
+    fn next_cursor_is_the_last_key_when_more_matched() {
+        let page = Page::from_matches(vec![item("a"), item("b"), item("c")], 2);
+
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.next_cursor.as_deref(), Some("b"));
+
+        assert_eq!(Page::from_matches(vec![item("a"), item("b")], 2).next_cursor, None);
+    }
+}
@@ -0,0 +1,23 @@
+use super::{Record, Storage, StorageError, Table};
+
+/// Keeps nothing; the state lives only as long as the process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStorage;
+
+impl<T> Storage<T> for MemoryStorage {
+    fn load(&mut self) -> Result<Table<T>, StorageError> {
+        Ok(Table::default())
+    }
+
+    fn append(&mut self, _: &Record<T>) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn wants_snapshot(&self) -> bool {
+        false
+    }
+
+    fn snapshot(&mut self, _: &Table<T>) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::{collections::BTreeMap, fmt::{Display, Formatter}, path::PathBuf};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::version::Versioned;
+
+pub mod memory;
+pub mod wal;
+
+pub use memory::MemoryStorage;
+pub use wal::WalStorage;
+
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 1000;
+
+/// The full contents of an `AppState`, ordered by key.
+///
+/// `version` is the last version handed out. It is kept separately from the
+/// values so that versions are never reused, even after the key that held the
+/// highest one has been removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Table<T> {
+    pub version: u64,
+    pub values: BTreeMap<String, Versioned<T>>,
+}
+
+impl<T> Default for Table<T> {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            values: BTreeMap::new(),
+        }
+    }
+}
+
+/// A single mutation of the state, as written to durable storage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Record<T> {
+    Set { key: String, version: u64, value: T },
+    Remove { key: String, version: u64 },
+}
+
+impl<T> Record<T> {
+    pub fn key(&self) -> &str {
+        match self {
+            Record::Set { key, .. } | Record::Remove { key, .. } => key,
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        match self {
+            Record::Set { version, .. } | Record::Remove { version, .. } => *version,
+        }
+    }
+
+    pub fn apply(self, table: &mut Table<T>) {
+        table.version = table.version.max(self.version());
+
+        match self {
+            Record::Set { key, version, value } => {
+                table.values.insert(key, Versioned::new(version, value));
+            }
+            Record::Remove { key, .. } => {
+                table.values.remove(&key);
+            }
+        }
+    }
+}
+
+/// Backend that keeps the contents of an `AppState` across restarts.
+///
+/// Every mutation is passed to `append` before it is applied in memory, so a
+/// failed append leaves the state untouched.
+pub trait Storage<T>: Send {
+    fn load(&mut self) -> Result<Table<T>, StorageError>;
+
+    fn append(&mut self, record: &Record<T>) -> Result<(), StorageError>;
+
+    fn wants_snapshot(&self) -> bool;
+
+    fn snapshot(&mut self, table: &Table<T>) -> Result<(), StorageError>;
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    Serde(serde_json::Error),
+    Config(String),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "storage io error: {e}"),
+            StorageError::Serde(e) => write!(f, "storage encoding error: {e}"),
+            StorageError::Config(e) => write!(f, "storage config error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Serde(e)
+    }
+}
+
+/// Builds the storage backend selected by `STATE_BACKEND`.
+///
+/// * `memory` (default) keeps nothing across restarts.
+/// * `wal` writes to `STATE_PATH`, snapshotting every
+///   `STATE_SNAPSHOT_INTERVAL` writes. `STATE_FSYNC=false` skips the fsync
+///   after each append.
+pub fn from_env<T>() -> Result<Box<dyn Storage<T>>, StorageError>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    let backend = std::env::var("STATE_BACKEND").unwrap_or("memory".to_string());
+
+    info!("using state backend={backend}");
+
+    match backend.as_str() {
+        "memory" => Ok(Box::new(MemoryStorage)),
+        "wal" => {
+            let path = match std::env::var("STATE_PATH") {
+                Ok(path) => PathBuf::from(path),
+                Err(_) => return Err(StorageError::Config("STATE_PATH not set".to_string())),
+            };
+
+            let snapshot_interval = match std::env::var("STATE_SNAPSHOT_INTERVAL") {
+                Ok(interval) => interval.parse().map_err(|_| {
+                    StorageError::Config(format!("invalid STATE_SNAPSHOT_INTERVAL={interval}"))
+                })?,
+                Err(_) => {
+                    warn!("using default snapshot interval={DEFAULT_SNAPSHOT_INTERVAL}");
+                    DEFAULT_SNAPSHOT_INTERVAL
+                }
+            };
+
+            let fsync = std::env::var("STATE_FSYNC")
+                .map(|value| value != "false")
+                .unwrap_or(true);
+
+            Ok(Box::new(WalStorage::open(path, snapshot_interval, fsync)?))
+        }
+        other => Err(StorageError::Config(format!("unknown STATE_BACKEND={other}"))),
+    }
+}
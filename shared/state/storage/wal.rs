@@ -0,0 +1,336 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{error, info, warn};
+
+use super::{Record, Storage, StorageError, Table};
+
+const WAL_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.json.tmp";
+
+/// Append-only write-ahead log with periodic snapshots.
+///
+/// Every record is written as one JSON line to `wal.log`. Once
+/// `snapshot_interval` records have been appended the full state is written to
+/// `snapshot.json` and the log is truncated. On load the snapshot is read and
+/// the log replayed over it; replaying is idempotent, so a crash between
+/// writing the snapshot and truncating the log is harmless. A record torn by
+/// a crash mid-append is cut off the log before anything else is written,
+/// and one that fails mid-append is cut off straight away.
+pub struct WalStorage<T> {
+    dir: PathBuf,
+    wal: File,
+    snapshot_interval: u64,
+    appended: u64,
+    fsync: bool,
+    /// Set when a failed append couldn't be cut off the log, which then can't
+    /// be trusted with more records.
+    failed: bool,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> WalStorage<T> {
+    pub fn open(dir: PathBuf, snapshot_interval: u64, fsync: bool) -> Result<Self, StorageError> {
+        fs::create_dir_all(&dir)?;
+
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(WAL_FILE))?;
+
+        info!("opened wal: dir={}", dir.display());
+
+        Ok(Self {
+            dir,
+            wal,
+            snapshot_interval,
+            appended: 0,
+            fsync,
+            failed: false,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T> Storage<T> for WalStorage<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn load(&mut self) -> Result<Table<T>, StorageError> {
+        let snapshot_path = self.dir.join(SNAPSHOT_FILE);
+
+        let mut table: Table<T> = match File::open(&snapshot_path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Table::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        info!("loaded snapshot: items={}, version={}", table.values.len(), table.version);
+
+        let mut wal = BufReader::new(File::open(self.dir.join(WAL_FILE))?);
+
+        // End of the last complete record; anything after it is torn.
+        let mut good = 0u64;
+        let mut line = String::new();
+        let mut line_number = 0;
+
+        loop {
+            line.clear();
+
+            let read = wal.read_line(&mut line)?;
+
+            if read == 0 {
+                break;
+            }
+
+            line_number += 1;
+
+            // Only the tail can be torn by a crash mid-write, and a record
+            // missing its newline was never acknowledged.
+            let record = match line.strip_suffix('\n') {
+                Some(record) => serde_json::from_str::<Record<T>>(record).map_err(|e| e.to_string()),
+                None => Err("missing newline".to_string()),
+            };
+
+            match record {
+                Ok(record) => {
+                    record.apply(&mut table);
+                    self.appended += 1;
+                    good += read as u64;
+                }
+                Err(e) => {
+                    warn!("stopping wal replay at torn record: line={line_number}, error={e}");
+                    break;
+                }
+            }
+        }
+
+        let len = self.wal.metadata()?.len();
+
+        if len > good {
+            warn!("truncating torn wal tail: offset={good}, bytes={}", len - good);
+
+            // Later appends would otherwise be glued onto the torn record and
+            // lost with it on the next replay.
+            self.wal.set_len(good)?;
+            self.wal.sync_all()?;
+        }
+
+        info!("replayed wal: records={}, items={}, version={}", self.appended, table.values.len(), table.version);
+
+        Ok(table)
+    }
+
+    fn append(&mut self, record: &Record<T>) -> Result<(), StorageError> {
+        if self.failed {
+            return Err(StorageError::Io(std::io::Error::other("wal failed earlier, refusing writes until restarted")));
+        }
+
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let len = self.wal.metadata()?.len();
+
+        let written = self.wal.write_all(&line).and_then(|_| match self.fsync {
+            true => self.wal.sync_data(),
+            false => Ok(()),
+        });
+
+        if let Err(e) = written {
+            // The caller is told the write failed, so none of it may be
+            // replayed. The log is opened in append mode, so later writes
+            // land at the restored end.
+            if let Err(rollback) = self.wal.set_len(len).and_then(|_| self.wal.sync_data()) {
+                error!("unable to roll back failed wal append, refusing further writes: error={rollback}");
+                self.failed = true;
+            }
+
+            return Err(e.into());
+        }
+
+        self.appended += 1;
+
+        Ok(())
+    }
+
+    fn wants_snapshot(&self) -> bool {
+        self.appended >= self.snapshot_interval
+    }
+
+    fn snapshot(&mut self, table: &Table<T>) -> Result<(), StorageError> {
+        let tmp_path = self.dir.join(SNAPSHOT_TMP_FILE);
+
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, table)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+
+        // The rename is only durable once the directory entry is.
+        sync_dir(&self.dir)?;
+
+        // The log is opened in append mode, so later writes land at the new end.
+        self.wal.set_len(0)?;
+
+        info!("wrote snapshot: items={}, records={}, version={}", table.values.len(), self.appended, table.version);
+
+        self.appended = 0;
+
+        Ok(())
+    }
+}
+
+fn sync_dir(dir: &Path) -> Result<(), StorageError> {
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+
+        std::env::temp_dir().join(format!("wal-{name}-{}-{nanos}", std::process::id()))
+    }
+
+    fn set(key: &str, version: u64) -> Record<String> {
+        Record::Set {
+            key: key.to_string(),
+            version,
+            value: format!("value-{version}"),
+        }
+    }
+
+    fn reopen(dir: &Path) -> (WalStorage<String>, Table<String>) {
+        let mut wal = WalStorage::open(dir.to_path_buf(), 1000, true).unwrap();
+        let table = wal.load().unwrap();
+
+        (wal, table)
+    }
+
+    #[test]
+    fn replays_log_over_snapshot() {
+        let dir = temp_dir("replay");
+
+        let (mut wal, mut table) = reopen(&dir);
+
+        for record in [set("a", 1), set("b", 2)] {
+            wal.append(&record).unwrap();
+            record.apply(&mut table);
+        }
+
+        wal.snapshot(&table).unwrap();
+        wal.append(&Record::Remove { key: "a".to_string(), version: 3 }).unwrap();
+
+        let (_, table) = reopen(&dir);
+
+        assert_eq!(table.version, 3);
+        assert_eq!(table.values.keys().collect::<Vec<_>>(), ["b"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_torn_tail_before_appending() {
+        let dir = temp_dir("torn");
+
+        let (mut wal, _) = reopen(&dir);
+        wal.append(&set("a", 1)).unwrap();
+        wal.append(&set("b", 2)).unwrap();
+        drop(wal);
+
+        let good = fs::metadata(dir.join(WAL_FILE)).unwrap().len();
+
+        // A crash part way through the third record.
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .unwrap()
+            .write_all(br#"{"op":"set","key":"c","vers"#)
+            .unwrap();
+
+        let (mut wal, table) = reopen(&dir);
+
+        assert_eq!(table.version, 2);
+        assert_eq!(fs::metadata(dir.join(WAL_FILE)).unwrap().len(), good);
+
+        wal.append(&set("c", 3)).unwrap();
+        drop(wal);
+
+        let (_, table) = reopen(&dir);
+
+        assert_eq!(table.version, 3);
+        assert_eq!(table.values["c"].value, "value-3");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_appends_after_a_failed_rollback() {
+        let dir = temp_dir("failed");
+
+        let (mut wal, _) = reopen(&dir);
+        wal.append(&set("a", 1)).unwrap();
+
+        // A handle that can neither write nor truncate.
+        wal.wal = File::open(dir.join(WAL_FILE)).unwrap();
+
+        assert!(wal.append(&set("b", 2)).is_err());
+        assert!(wal.failed);
+
+        wal.wal = OpenOptions::new().append(true).open(dir.join(WAL_FILE)).unwrap();
+
+        assert!(wal.append(&set("b", 2)).is_err());
+        drop(wal);
+
+        let (_, table) = reopen(&dir);
+
+        assert_eq!(table.version, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_record_missing_its_newline() {
+        let dir = temp_dir("newline");
+
+        let (mut wal, _) = reopen(&dir);
+        wal.append(&set("a", 1)).unwrap();
+        drop(wal);
+
+        // The whole record made it, but not the newline after it.
+        let line = serde_json::to_vec(&set("b", 2)).unwrap();
+
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .unwrap()
+            .write_all(&line)
+            .unwrap();
+
+        let (mut wal, table) = reopen(&dir);
+
+        assert_eq!(table.version, 1);
+
+        wal.append(&set("c", 2)).unwrap();
+        drop(wal);
+
+        let (_, table) = reopen(&dir);
+
+        assert_eq!(table.values.keys().collect::<Vec<_>>(), ["a", "c"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
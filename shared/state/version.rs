@@ -0,0 +1,284 @@
+use std::fmt::{Display, Formatter};
+
+use axum::http::{header, HeaderMap, HeaderName};
+use serde::{Deserialize, Serialize};
+
+use super::StateError;
+
+/// A stored value together with the version it was written at.
+///
+/// Versions come from a single counter per `AppState`, so they increase
+/// monotonically across every key and are never reused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub version: u64,
+    pub value: T,
+}
+
+impl<T> Versioned<T> {
+    pub fn new(version: u64, value: T) -> Self {
+        Self { version, value }
+    }
+
+    pub fn etag(&self) -> String {
+        format_etag(self.version)
+    }
+}
+
+impl<T: Display> Display for Versioned<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "version={}, {}", self.version, self.value)
+    }
+}
+
+pub fn format_etag(version: u64) -> String {
+    format!("\"{version}\"")
+}
+
+/// The version in a strong ETag of ours, e.g. `"3"`.
+pub fn parse_etag(tag: &str) -> Option<u64> {
+    tag.trim()
+        .strip_prefix('"')
+        .and_then(|tag| tag.strip_suffix('"'))
+        .and_then(|tag| tag.parse().ok())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ETagMatch {
+    Any,
+    /// Versions of the listed tags, with `true` marking weak tags.
+    Tags(Vec<(u64, bool)>),
+}
+
+impl ETagMatch {
+    fn parse(value: &str) -> Self {
+        if value.trim() == "*" {
+            return ETagMatch::Any;
+        }
+
+        // Tags that aren't ours can never match, so they are dropped here.
+        let tags = value
+            .split(',')
+            .filter_map(|tag| {
+                let tag = tag.trim();
+                let (tag, weak) = match tag.strip_prefix("W/") {
+                    Some(tag) => (tag, true),
+                    None => (tag, false),
+                };
+
+                parse_etag(tag).map(|version| (version, weak))
+            })
+            .collect();
+
+        ETagMatch::Tags(tags)
+    }
+
+    fn header_value(&self) -> String {
+        match self {
+            ETagMatch::Any => "*".to_string(),
+            ETagMatch::Tags(tags) => tags
+                .iter()
+                .map(|(version, weak)| match weak {
+                    true => format!("W/{}", format_etag(*version)),
+                    false => format_etag(*version),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+
+    fn strong_match(&self, version: u64) -> bool {
+        match self {
+            ETagMatch::Any => true,
+            ETagMatch::Tags(tags) => tags.iter().any(|(tag, weak)| !weak && *tag == version),
+        }
+    }
+
+    fn weak_match(&self, version: u64) -> bool {
+        match self {
+            ETagMatch::Any => true,
+            ETagMatch::Tags(tags) => tags.iter().any(|(tag, _)| *tag == version),
+        }
+    }
+}
+
+/// Conditional request headers (`If-Match` / `If-None-Match`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Precondition {
+    if_match: Option<ETagMatch>,
+    if_none_match: Option<ETagMatch>,
+}
+
+impl Precondition {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn if_match(version: u64) -> Self {
+        Self {
+            if_match: Some(ETagMatch::Tags(vec![(version, false)])),
+            if_none_match: None,
+        }
+    }
+
+    /// `If-None-Match: *`: only if nothing is stored.
+    pub fn if_absent() -> Self {
+        Self {
+            if_match: None,
+            if_none_match: Some(ETagMatch::Any),
+        }
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let parse = |name| {
+            let values: Vec<&str> = headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect();
+
+            match values.is_empty() {
+                true => None,
+                false => Some(ETagMatch::parse(&values.join(","))),
+            }
+        };
+
+        Self {
+            if_match: parse(header::IF_MATCH),
+            if_none_match: parse(header::IF_NONE_MATCH),
+        }
+    }
+
+    /// The headers that have another service check this precondition.
+    pub fn to_headers(&self) -> Vec<(HeaderName, String)> {
+        [(header::IF_MATCH, &self.if_match), (header::IF_NONE_MATCH, &self.if_none_match)]
+            .into_iter()
+            .filter_map(|(name, condition)| condition.as_ref().map(|condition| (name, condition.header_value())))
+            .collect()
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.if_match.is_none() && self.if_none_match.is_none()
+    }
+
+    /// `If-None-Match: *` on its own, i.e. create-only.
+    pub fn requires_absent(&self) -> bool {
+        self.if_match.is_none() && self.if_none_match == Some(ETagMatch::Any)
+    }
+
+    /// Checks a write against the version currently stored for the key.
+    pub fn evaluate(&self, current: Option<u64>) -> Result<(), StateError> {
+        let if_match_passed = match (&self.if_match, current) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(condition), Some(version)) => condition.strong_match(version),
+        };
+
+        let if_none_match_passed = match (&self.if_none_match, current) {
+            (None, _) | (Some(_), None) => true,
+            (Some(condition), Some(version)) => !condition.weak_match(version),
+        };
+
+        match if_match_passed && if_none_match_passed {
+            true => Ok(()),
+            false => Err(StateError::PreconditionFailed { current }),
+        }
+    }
+
+    /// Whether a read of `version` can be answered with `304 Not Modified`.
+    pub fn not_modified(&self, version: u64) -> bool {
+        match &self.if_none_match {
+            Some(condition) => condition.weak_match(version),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn precondition(headers: &[(header::HeaderName, &'static str)]) -> Precondition {
+        let mut map = HeaderMap::new();
+
+        for (name, value) in headers {
+            map.append(name, HeaderValue::from_static(value));
+        }
+
+        Precondition::from_headers(&map)
+    }
+
+    fn failed(result: Result<(), StateError>) -> bool {
+        matches!(result, Err(StateError::PreconditionFailed { .. }))
+    }
+
+    #[test]
+    fn if_match_needs_a_strong_match_on_an_existing_key() {
+        let condition = precondition(&[(header::IF_MATCH, "\"1\", \"3\"")]);
+
+        assert!(condition.evaluate(Some(3)).is_ok());
+        assert!(failed(condition.evaluate(Some(2))));
+        assert!(failed(condition.evaluate(None)));
+
+        assert!(failed(precondition(&[(header::IF_MATCH, "W/\"3\"")]).evaluate(Some(3))));
+        assert!(failed(precondition(&[(header::IF_MATCH, "*")]).evaluate(None)));
+        assert!(precondition(&[(header::IF_MATCH, "*")]).evaluate(Some(7)).is_ok());
+    }
+
+    #[test]
+    fn if_none_match_fails_on_a_weak_match() {
+        let condition = precondition(&[(header::IF_NONE_MATCH, "W/\"3\"")]);
+
+        assert!(failed(condition.evaluate(Some(3))));
+        assert!(condition.evaluate(Some(4)).is_ok());
+        assert!(condition.evaluate(None).is_ok());
+
+        let create_only = precondition(&[(header::IF_NONE_MATCH, "*")]);
+
+        assert!(create_only.requires_absent());
+        assert!(create_only.evaluate(None).is_ok());
+        assert!(failed(create_only.evaluate(Some(1))));
+    }
+
+    #[test]
+    fn joins_repeated_headers_and_ignores_foreign_tags() {
+        let condition = precondition(&[(header::IF_MATCH, "\"abc\""), (header::IF_MATCH, "\"5\"")]);
+
+        assert!(condition.evaluate(Some(5)).is_ok());
+        assert!(failed(precondition(&[(header::IF_MATCH, "\"abc\"")]).evaluate(Some(5))));
+        assert!(precondition(&[]).is_none());
+    }
+
+    #[test]
+    fn round_trips_through_headers() {
+        let conditions = [
+            Precondition::none(),
+            Precondition::if_match(4),
+            Precondition::if_absent(),
+            precondition(&[(header::IF_NONE_MATCH, "\"2\", W/\"3\"")]),
+        ];
+
+        for condition in conditions {
+            let mut headers = HeaderMap::new();
+
+            for (name, value) in condition.to_headers() {
+                headers.append(name, value.parse().unwrap());
+            }
+
+            assert_eq!(Precondition::from_headers(&headers), condition);
+        }
+
+        assert_eq!(parse_etag(&format_etag(12)), Some(12));
+        assert_eq!(parse_etag("W/\"12\""), None);
+    }
+
+    #[test]
+    fn not_modified_on_any_if_none_match_hit() {
+        assert!(precondition(&[(header::IF_NONE_MATCH, "\"2\", W/\"3\"")]).not_modified(3));
+        assert!(precondition(&[(header::IF_NONE_MATCH, "*")]).not_modified(1));
+        assert!(!precondition(&[(header::IF_NONE_MATCH, "\"2\"")]).not_modified(3));
+        assert!(!precondition(&[(header::IF_MATCH, "\"3\"")]).not_modified(3));
+    }
+}
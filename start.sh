@@ -13,6 +13,8 @@ docker-compose up -d
 
 # Setup kafka topics
 docker-compose exec kafka kafka-topics.sh --create --topic log_sink --partitions 1 --replication-factor 1 --bootstrap-server kafka:9092
+docker-compose exec kafka kafka-topics.sh --create --topic entity_changed --partitions 1 --replication-factor 1 --bootstrap-server kafka:9092
+docker-compose exec kafka kafka-topics.sh --create --topic property_changed --partitions 1 --replication-factor 1 --bootstrap-server kafka:9092
 
 # Setup ksqldb
 cat log_process.ksql | docker exec -i ksqldb-cli ksql http://ksqldb-server:8088
\ No newline at end of file